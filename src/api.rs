@@ -0,0 +1,303 @@
+use anyhow::Result;
+use backoff::{ExponentialBackoff, backoff::Backoff};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use rand::{seq::SliceRandom, SeedableRng};
+use rand::rngs::StdRng;
+use crate::models::ListingItem;
+use crate::nav::PoliteKnobs;
+use crate::throttle::{gate, set_cooldown_secs, Limiter};
+
+static UAS: &[&str] = &[
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 13_2) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.2 Safari/605.1.15",
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/125.0.0.0 Safari/537.36",
+];
+
+pub fn make_client(proxy: Option<&str>, worker_id: usize, timeout: std::time::Duration) -> Result<Client> {
+    let mut rng = StdRng::seed_from_u64(1000 + worker_id as u64);
+    let ua = *UAS.choose(&mut rng).unwrap();
+    let mut b = Client::builder()
+        .user_agent(ua)
+        .timeout(timeout);
+    if let Some(p) = proxy {
+        b = b.proxy(reqwest::Proxy::all(p)?);
+    }
+    Ok(b.build()?)
+}
+
+// `https://old.reddit.com/r/x/top/?t=day` → `https://old.reddit.com/r/x/top/.json?t=day&raw_json=1`
+pub fn json_url(url: &str) -> String {
+    let (path, query) = match url.split_once('?') {
+        Some((p, q)) => (p, Some(q)),
+        None => (url, None),
+    };
+    let path = path.trim_end_matches('/');
+    match query {
+        Some(q) if !q.is_empty() => format!("{path}/.json?{q}&raw_json=1"),
+        _ => format!("{path}/.json?raw_json=1"),
+    }
+}
+
+pub async fn polite_get_json(
+    client: &Client,
+    limiter: &Limiter,
+    url: &str,
+    knobs: PoliteKnobs,
+) -> Result<Option<Value>> {
+    let mut eb = ExponentialBackoff {
+        current_interval: std::time::Duration::from_millis(knobs.initial_ms),
+        initial_interval: std::time::Duration::from_millis(knobs.initial_ms),
+        max_interval:     std::time::Duration::from_millis(knobs.max_ms),
+        max_elapsed_time: Some(std::time::Duration::from_secs(15)),
+        ..ExponentialBackoff::default()
+    };
+    for i in 0..knobs.attempts {
+        gate(limiter).await;
+        let resp = client.get(url).send().await?;
+        let status = resp.status();
+        if status != StatusCode::TOO_MANY_REQUESTS {
+            if !status.is_success() {
+                if knobs.verbose {
+                    eprintln!("[HTTP {}] {url}", status.as_u16());
+                }
+                return Ok(None);
+            }
+            if knobs.verbose && i > 0 {
+                eprintln!("[RECOVERED] {url} after attempt {}", i+1);
+            }
+            return Ok(Some(resp.json::<Value>().await?));
+        }
+        let sleep = eb.next_backoff().unwrap_or(std::time::Duration::from_millis(1200));
+        if knobs.verbose {
+            eprintln!("[429] {url} → backoff {}ms (attempt {}/{})", sleep.as_millis(), i+1, knobs.attempts);
+        }
+        set_cooldown_secs(20 + (i as u64) * 10);
+        tokio::time::sleep(sleep).await;
+    }
+    if knobs.verbose {
+        eprintln!("[GAVE UP] {url}");
+    }
+    Ok(None)
+}
+
+fn children(listing: &Value) -> Vec<Value> {
+    listing.pointer("/data/children").and_then(|x| x.as_array()).cloned().unwrap_or_default()
+}
+
+fn ts(v: Option<&Value>) -> Option<i64> {
+    v.and_then(|x| x.as_f64()).map(|f| f as i64)
+}
+
+fn is_image_url(u: &str) -> bool {
+    let path = u.split('?').next().unwrap_or(u).to_lowercase();
+    [".jpg", ".jpeg", ".png", ".gif", ".webp"].iter().any(|e| path.ends_with(e))
+}
+
+fn media_item(kind: &str, url: Option<&str>) -> Value {
+    json!({
+        "kind": kind, "url": url, "caption": null, "mime": null,
+        "width": null, "height": null, "duration": null, "fallback_url": null,
+    })
+}
+
+// Ordered media of a post: gallery items with captions, hosted video, a direct image or the link
+// target, then the preview renditions. A crosspost's media lives on its parent.
+fn media_json(post: &Value) -> Vec<Value> {
+    let src = post.pointer("/crosspost_parent_list/0").unwrap_or(post);
+    let mut out: Vec<Value> = vec![];
+
+    if let Some(items) = src.pointer("/gallery_data/items").and_then(|x| x.as_array()) {
+        for it in items {
+            let Some(mid) = it.get("media_id").and_then(|x| x.as_str()) else { continue };
+            let Some(meta) = src.pointer(&format!("/media_metadata/{mid}")) else { continue };
+            let s = meta.get("s").cloned().unwrap_or(Value::Null);
+            let url = s.get("u").or(s.get("gif")).or(s.get("mp4")).and_then(|x| x.as_str());
+            let mut m = media_item("gallery", url);
+            m["caption"] = it.get("caption").cloned().unwrap_or(Value::Null);
+            m["mime"] = meta.get("m").cloned().unwrap_or(Value::Null);
+            m["width"] = s.get("x").cloned().unwrap_or(Value::Null);
+            m["height"] = s.get("y").cloned().unwrap_or(Value::Null);
+            out.push(m);
+        }
+    }
+
+    let video = src.pointer("/secure_media/reddit_video").or(src.pointer("/media/reddit_video"))
+        .or(src.pointer("/preview/reddit_video_preview"));
+    if let Some(v) = video {
+        let mut m = media_item("video", src.get("url").and_then(|x| x.as_str()));
+        m["duration"] = v.get("duration").cloned().unwrap_or(Value::Null);
+        m["width"] = v.get("width").cloned().unwrap_or(Value::Null);
+        m["height"] = v.get("height").cloned().unwrap_or(Value::Null);
+        m["fallback_url"] = v.get("fallback_url").cloned().unwrap_or(Value::Null);
+        out.push(m);
+    }
+
+    let target = src.get("url_overridden_by_dest").or(src.get("url")).and_then(|x| x.as_str());
+    let is_self = src.get("is_self").and_then(|x| x.as_bool()).unwrap_or(false);
+    if out.is_empty() {
+        if let Some(u) = target.filter(|u| is_image_url(u)) {
+            let mut m = media_item("image", Some(u));
+            m["width"] = src.pointer("/preview/images/0/source/width").cloned().unwrap_or(Value::Null);
+            m["height"] = src.pointer("/preview/images/0/source/height").cloned().unwrap_or(Value::Null);
+            out.push(m);
+        } else if let Some(u) = target.filter(|u| !is_self && !u.contains("reddit.com/gallery/")) {
+            out.push(media_item("link", Some(u)));
+        }
+    }
+
+    if let Some(prev) = src.pointer("/preview/images").and_then(|x| x.as_array()) {
+        for p in prev {
+            let Some(u) = p.pointer("/source/url").and_then(|x| x.as_str()) else { continue };
+            if out.iter().any(|m| m["url"].as_str() == Some(u)) { continue; }
+            let mut m = media_item("preview", Some(u));
+            m["width"] = p.pointer("/source/width").cloned().unwrap_or(Value::Null);
+            m["height"] = p.pointer("/source/height").cloned().unwrap_or(Value::Null);
+            out.push(m);
+        }
+    }
+
+    for (i, m) in out.iter_mut().enumerate() { m["position"] = json!(i); }
+    out
+}
+
+pub fn listing_json(v: &Value) -> (Vec<ListingItem>, Option<String>) {
+    let items = children(v).into_iter().filter_map(|c| {
+        if c.get("kind").and_then(|x| x.as_str()) != Some("t3") { return None; }
+        let d = c.get("data")?;
+        let id = d.get("id")?.as_str()?.to_string();
+        let href = d.get("permalink").and_then(|x| x.as_str()).map(|p| format!("https://old.reddit.com{p}"));
+        Some((id, href, ts(d.get("created_utc"))))
+    }).collect();
+    let after = v.pointer("/data/after").and_then(|x| x.as_str()).map(|s| s.to_string());
+    (items, after)
+}
+
+// Rule titles from /about/rules.json, in order.
+pub fn rule_names(v: &Value) -> Option<Vec<String>> {
+    v.get("rules").and_then(|x| x.as_array()).map(|a| {
+        a.iter().filter_map(|r| r.get("short_name").and_then(|x| x.as_str()).map(|s| s.to_string())).collect()
+    })
+}
+
+// Same shape as extract::sidebar_old, from /about, /about/rules and /about/moderators. The
+// latter two are optional since they may be refused without a login.
+pub fn about_json(about: &Value, rules: Option<&Value>, mods: Option<&Value>) -> Value {
+    let d = about.get("data").cloned().unwrap_or(Value::Null);
+    let rules = rules.and_then(rule_names);
+    let mods = mods.and_then(|m| m.pointer("/data/children")).and_then(|x| x.as_array()).map(|a| {
+        a.iter().filter_map(|m| m.get("name").and_then(|x| x.as_str())).collect::<Vec<_>>()
+    });
+    json!({
+        "subscribers": d.get("subscribers"),
+        "users_online": d.get("active_user_count").or(d.get("accounts_active")),
+        "created_utc": ts(d.get("created_utc")),
+        "description": d.get("public_description").and_then(|x| x.as_str()).filter(|s| !s.is_empty()),
+        "rules": rules,
+        "moderators": mods,
+    })
+}
+
+// Comments found in a listing, plus the ids behind "load more comments" stubs and the
+// parent ids of "continue this thread" stubs (a `more` with no children).
+#[derive(Default)]
+pub struct CommentHarvest {
+    pub comments: Vec<Value>,
+    pub more_ids: Vec<String>,
+    pub continue_ids: Vec<String>,
+}
+
+fn harvest_thing(c: &Value, h: &mut CommentHarvest) {
+    let Some(d) = c.get("data") else { return };
+    match c.get("kind").and_then(|x| x.as_str()) {
+        Some("t1") => {
+            // hidden scores come back as a placeholder 1
+            let hidden = d.get("score_hidden").and_then(|x| x.as_bool()).unwrap_or(false);
+            // `edited` is false or the edit timestamp
+            let edited_utc = ts(d.get("edited"));
+            h.comments.push(json!({
+                "id": d.get("id"),
+                "parent_fullname": d.get("parent_id"),
+                "author": d.get("author"),
+                "body": d.get("body"),
+                "score": if hidden { None } else { d.get("score") },
+                "created_utc": ts(d.get("created_utc")),
+                "depth": d.get("depth"),
+                "permalink": d.get("permalink").and_then(|x| x.as_str()).map(|p| format!("https://old.reddit.com{p}")),
+                "edited": edited_utc.is_some() || d.get("edited").and_then(|x| x.as_bool()).unwrap_or(false),
+                "edited_utc": edited_utc,
+                "distinguished": d.get("distinguished"),
+                "stickied": d.get("stickied"),
+                "score_hidden": hidden,
+            }));
+            if let Some(replies) = d.get("replies").filter(|r| r.is_object()) {
+                for r in children(replies) { harvest_thing(&r, h); }
+            }
+        }
+        Some("more") => {
+            let kids: Vec<String> = d.get("children").and_then(|x| x.as_array())
+                .map(|a| a.iter().filter_map(|x| x.as_str().map(|s| s.to_string())).collect())
+                .unwrap_or_default();
+            if kids.is_empty() {
+                if let Some(p) = d.get("parent_id").and_then(|x| x.as_str()) {
+                    h.continue_ids.push(p.trim_start_matches("t1_").to_string());
+                }
+            } else {
+                h.more_ids.extend(kids);
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn harvest_listing(listing: &Value) -> CommentHarvest {
+    let mut h = CommentHarvest::default();
+    for c in children(listing) { harvest_thing(&c, &mut h); }
+    h
+}
+
+// /api/morechildren returns a flat list of things under json.data.things
+pub fn harvest_morechildren(v: &Value) -> CommentHarvest {
+    let mut h = CommentHarvest::default();
+    let things = v.pointer("/json/data/things").and_then(|x| x.as_array()).cloned().unwrap_or_default();
+    for c in things { harvest_thing(&c, &mut h); }
+    h
+}
+
+// Same shape as extract::post_old_page so the crawler can treat both backends alike.
+pub fn post_json(v: &Value) -> Value {
+    let arr = v.as_array().cloned().unwrap_or_default();
+    let post = arr.first().map(children).and_then(|c| c.into_iter().next())
+        .and_then(|c| c.get("data").cloned())
+        .unwrap_or(Value::Null);
+
+    let media = media_json(&post);
+
+    let h = arr.get(1).map(harvest_listing).unwrap_or_default();
+
+    let selftext = post.get("selftext").and_then(|x| x.as_str()).filter(|s| !s.is_empty());
+    json!({
+        "title": post.get("title"),
+        "author": post.get("author"),
+        "score": post.get("score"),
+        "created_utc": ts(post.get("created_utc")),
+        "selftext": selftext,
+        "num_comments": post.get("num_comments"),
+        "flair": post.get("link_flair_text"),
+        "over_18": post.get("over_18"),
+        "spoiler": post.get("spoiler"),
+        "stickied": post.get("stickied"),
+        "locked": post.get("locked"),
+        "domain": post.get("domain"),
+        "crosspost_parent": post.get("crosspost_parent"),
+        "gilded": post.get("gilded"),
+        "url": post.get("url_overridden_by_dest").or(post.get("url")),
+        "upvote_ratio": post.get("upvote_ratio"),
+        "removed_by_category": post.get("removed_by_category"),
+        "media": media,
+        "comments": h.comments,
+        "more_ids": h.more_ids,
+        "continue_ids": h.continue_ids,
+    })
+}
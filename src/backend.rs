@@ -0,0 +1,355 @@
+use anyhow::Result;
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::HashMap;
+use thirtyfour::prelude::*;
+
+use crate::api::{
+    about_json, harvest_listing, harvest_morechildren, json_url, listing_json, make_client, polite_get_json, post_json,
+    rule_names,
+};
+use crate::driver::make_driver;
+use crate::extract::{expand_old_morecomments, listing_old, page_json, post_old_page, sidebar_old};
+use crate::models::ListingItem;
+use crate::nav::{polite_get, PoliteKnobs};
+use crate::throttle::Limiter;
+
+// `url` with its `after` query pair set to `after`, keeping every other pair.
+fn with_after(url: &str, after: &str) -> String {
+    let Ok(mut u) = reqwest::Url::parse(url) else { return url.to_string() };
+    let kept: Vec<(String, String)> = u.query_pairs()
+        .filter(|(k, _)| k != "after")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    u.query_pairs_mut().clear().extend_pairs(kept).append_pair("after", after);
+    u.to_string()
+}
+
+// One per worker: either a Chrome session or a plain HTTP client hitting the .json endpoints.
+pub enum Backend {
+    Browser(WebDriver),
+    Json(Client),
+}
+
+pub struct BackendOpts<'a> {
+    pub mode: &'a str,
+    pub headless: bool,
+    pub user_data_dir: Option<&'a str>,
+    pub proxy: Option<&'a str>,
+    pub worker_id: usize,
+    pub webdriver_url: &'a str,
+}
+
+impl Backend {
+    pub async fn open(o: BackendOpts<'_>) -> Result<Backend> {
+        if o.mode == "json" {
+            return Ok(Backend::Json(make_client(o.proxy, o.worker_id, std::time::Duration::from_secs(30))?));
+        }
+        let drv = make_driver(o.headless, o.user_data_dir, None, o.proxy, o.worker_id, o.webdriver_url).await?;
+        Ok(Backend::Browser(drv))
+    }
+
+    // Ok(None) means we gave up on the page (429s / HTTP error); the item list may be
+    // empty on a valid but exhausted listing.
+    pub async fn listing(
+        &self, limiter: &Limiter, url: &str, knobs: PoliteKnobs,
+    ) -> Result<Option<(Vec<ListingItem>, Option<String>)>> {
+        match self {
+            Backend::Browser(drv) => {
+                if !polite_get(drv, limiter, url, knobs).await.unwrap_or(false) { return Ok(None); }
+                let items = listing_old(drv).await?;
+
+                let mut next_href = drv.find_all(By::Css("span.next-button > a")).await
+                    .ok().and_then(|mut v| v.pop())
+                    .and_then(|e| futures::executor::block_on(e.attr("href")).ok().flatten());
+
+                if next_href.is_none() {
+                    next_href = drv.execute("return (document.querySelector('span.next-button > a')||{}).href;", vec![])
+                        .await.ok()
+                        .and_then(|ret| ret.convert::<Option<String>>().ok())
+                        .flatten();
+                }
+                Ok(Some((items, next_href)))
+            }
+            Backend::Json(client) => {
+                let Some(v) = polite_get_json(client, limiter, &json_url(url), knobs).await? else { return Ok(None) };
+                let (items, after) = listing_json(&v);
+                // keep the human URL as the cursor so both backends page the same way
+                let next = after.map(|a| with_after(url, &a));
+                Ok(Some((items, next)))
+            }
+        }
+    }
+
+    // Subreddit sidebar data. The browser reads the listing page it is on, so call this right
+    // after the first `listing`, then loads the rules JSON, leaving that page; the JSON client
+    // fetches the /about endpoints.
+    pub async fn sidebar(&self, limiter: &Limiter, sub: &str, knobs: PoliteKnobs) -> Result<Option<Value>> {
+        match self {
+            Backend::Browser(drv) => {
+                let mut side = sidebar_old(drv).await?;
+                let url = json_url(&format!("https://old.reddit.com/r/{sub}/about/rules"));
+                if polite_get(drv, limiter, &url, knobs).await.unwrap_or(false) {
+                    // rules are optional: a page that won't parse leaves them null
+                    if let Some(rules) = page_json(drv).await.ok().flatten().as_ref().and_then(rule_names) {
+                        side["rules"] = Value::from(rules);
+                    }
+                }
+                Ok(Some(side))
+            }
+            Backend::Json(client) => {
+                let base = format!("https://old.reddit.com/r/{sub}/about");
+                let Some(about) = polite_get_json(client, limiter, &json_url(&base), knobs).await? else { return Ok(None) };
+                let rules = polite_get_json(client, limiter, &json_url(&format!("{base}/rules")), knobs).await?;
+                let mods = polite_get_json(client, limiter, &json_url(&format!("{base}/moderators")), knobs).await?;
+                Ok(Some(about_json(&about, rules.as_ref(), mods.as_ref())))
+            }
+        }
+    }
+
+    // With `expand = Some(max)` the comment tree is grown past Reddit's first render by following
+    // "load more comments" and "continue this thread" stubs until nothing is left or `max` is
+    // reached. Either way the returned value carries `tree_complete`.
+    pub async fn post(
+        &self, limiter: &Limiter, post_id: &str, knobs: PoliteKnobs, expand: Option<usize>,
+    ) -> Result<Option<Value>> {
+        let post_url = format!("https://old.reddit.com/comments/{}/", post_id);
+        let mut v = match self {
+            Backend::Browser(drv) => {
+                if !polite_get(drv, limiter, &post_url, knobs).await.unwrap_or(false) { return Ok(None); }
+                let expanded = match expand {
+                    Some(max) => expand_old_morecomments(drv, limiter, max).await?,
+                    None => true,
+                };
+                let mut v = post_old_page(drv).await?;
+                let complete = match expand {
+                    Some(max) => expanded && self.follow_continue_links(limiter, knobs, &mut v, max).await?,
+                    None => stubs_left(&v) == 0,
+                };
+                v["tree_complete"] = Value::Bool(complete);
+                v
+            }
+            Backend::Json(client) => {
+                let url = format!("{}&limit=500", json_url(&post_url));
+                let Some(raw) = polite_get_json(client, limiter, &url, knobs).await? else { return Ok(None) };
+                let mut v = post_json(&raw);
+                let complete = match expand {
+                    Some(max) => expand_json(client, limiter, knobs, post_id, &mut v, max).await?,
+                    None => stubs_left(&v) == 0,
+                };
+                v["tree_complete"] = Value::Bool(complete);
+                v
+            }
+        };
+        if expand.is_some() { chain_depths(&mut v); }
+        if let Some(obj) = v.as_object_mut() {
+            for k in ["more_stubs", "continue_links", "more_ids", "continue_ids"] { obj.remove(k); }
+        }
+        Ok(Some(v))
+    }
+
+    async fn follow_continue_links(
+        &self, limiter: &Limiter, knobs: PoliteKnobs, v: &mut Value, max: usize,
+    ) -> Result<bool> {
+        let Backend::Browser(drv) = self else { return Ok(true) };
+        let mut queue: Vec<String> = str_list(v, "continue_links");
+        let mut seen: Vec<String> = vec![];
+        let mut complete = true;
+        while let Some(link) = queue.pop() {
+            if seen.contains(&link) { continue; }
+            seen.push(link.clone());
+            if comment_count(v) >= max { return Ok(false); }
+            if !polite_get(drv, limiter, &link, knobs).await.unwrap_or(false) { complete = false; continue; }
+            complete &= expand_old_morecomments(drv, limiter, max.saturating_sub(comment_count(v))).await?;
+            let page = post_old_page(drv).await?;
+            merge_comments(v, &page["comments"]);
+            queue.extend(str_list(&page, "continue_links"));
+        }
+        Ok(complete)
+    }
+
+    pub async fn alive(&self) -> bool {
+        match self {
+            Backend::Browser(drv) => drv.current_url().await.is_ok(),
+            Backend::Json(_) => true,
+        }
+    }
+
+    pub async fn quit(self) {
+        if let Backend::Browser(drv) = self {
+            let _ = drv.quit().await;
+        }
+    }
+}
+
+fn str_list(v: &Value, key: &str) -> Vec<String> {
+    v.get(key).and_then(|x| x.as_array())
+        .map(|a| a.iter().filter_map(|x| x.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default()
+}
+
+fn comment_count(v: &Value) -> usize {
+    v.get("comments").and_then(|x| x.as_array()).map(|a| a.len()).unwrap_or(0)
+}
+
+fn stubs_left(v: &Value) -> usize {
+    v.get("more_stubs").and_then(|x| x.as_u64()).unwrap_or(0) as usize
+        + str_list(v, "continue_links").len()
+        + str_list(v, "more_ids").len()
+        + str_list(v, "continue_ids").len()
+}
+
+// Appends comments not already present (permalink pages repeat their parent chain).
+fn merge_comments(v: &mut Value, extra: &Value) {
+    let Some(extra) = extra.as_array() else { return };
+    let have: std::collections::HashSet<String> = v.get("comments").and_then(|x| x.as_array())
+        .map(|a| a.iter().filter_map(|c| c.get("id").and_then(|x| x.as_str()).map(|s| s.to_string())).collect())
+        .unwrap_or_default();
+    let mut add: Vec<Value> = extra.iter()
+        .filter(|c| c.get("id").and_then(|x| x.as_str()).is_some_and(|id| !have.contains(id)))
+        .cloned().collect();
+    if let Some(arr) = v.get_mut("comments").and_then(|x| x.as_array_mut()) {
+        arr.append(&mut add);
+    }
+}
+
+// Comments from "continue this thread" pages carry depths relative to that page, where the
+// focused comment is 0. Re-derives every depth from the parent_fullname chain; comments whose
+// parent we don't have keep the depth they came with.
+fn chain_depths(v: &mut Value) {
+    let Some(comments) = v.get_mut("comments").and_then(|x| x.as_array_mut()) else { return };
+    let id_of = |c: &Value| c.get("id").and_then(|x| x.as_str()).unwrap_or("").to_string();
+    let parent: HashMap<String, Option<String>> = comments.iter()
+        .map(|c| (id_of(c), c.get("parent_fullname").and_then(|x| x.as_str()).map(|s| s.to_string())))
+        .collect();
+    let given: HashMap<String, Option<i64>> = comments.iter()
+        .map(|c| (id_of(c), c.get("depth").and_then(|x| x.as_i64())))
+        .collect();
+    let mut depth: HashMap<String, Option<i64>> = HashMap::new();
+    for c in comments.iter_mut() {
+        // walk up to a top-level comment or one whose parent is missing, then back down
+        let mut chain = vec![id_of(c)];
+        let base = loop {
+            let cur = chain.last().unwrap();
+            if let Some(d) = depth.get(cur) { break *d; }
+            match parent.get(cur).cloned().flatten() {
+                Some(p) if p.starts_with("t1_") && parent.contains_key(&p[3..]) && !chain.contains(&p[3..].to_string()) => {
+                    chain.push(p[3..].to_string());
+                }
+                Some(p) if p.starts_with("t3_") => break Some(-1),
+                _ => break given.get(cur).copied().flatten().map(|d| d - 1),
+            }
+        };
+        let mut d = base;
+        for id in chain.iter().rev() {
+            if let Some(known) = depth.get(id) { d = *known; continue; }
+            d = d.map(|x| x + 1);
+            depth.insert(id.clone(), d);
+        }
+        if let Some(d) = depth.get(&id_of(c)).copied().flatten() {
+            c["depth"] = Value::from(d);
+        }
+    }
+}
+
+async fn expand_json(
+    client: &Client, limiter: &Limiter, knobs: PoliteKnobs, post_id: &str, v: &mut Value, max: usize,
+) -> Result<bool> {
+    let mut more = str_list(v, "more_ids");
+    let mut cont = str_list(v, "continue_ids");
+    let mut complete = true;
+
+    while !more.is_empty() || !cont.is_empty() {
+        if comment_count(v) >= max { return Ok(false); }
+
+        let h = if !more.is_empty() {
+            // the endpoint takes at most 100 ids per call
+            let batch: Vec<String> = more.drain(..more.len().min(100)).collect();
+            let url = format!(
+                "https://old.reddit.com/api/morechildren.json?api_type=json&raw_json=1&link_id=t3_{}&children={}",
+                post_id, batch.join(",")
+            );
+            match polite_get_json(client, limiter, &url, knobs).await? {
+                Some(raw) => harvest_morechildren(&raw),
+                None => { complete = false; continue; }
+            }
+        } else {
+            let parent = cont.remove(0);
+            let url = format!("{}&limit=500", json_url(&format!("https://old.reddit.com/comments/{}/_/{}/", post_id, parent)));
+            match polite_get_json(client, limiter, &url, knobs).await? {
+                Some(raw) => raw.as_array().and_then(|a| a.get(1)).map(harvest_listing).unwrap_or_default(),
+                None => { complete = false; continue; }
+            }
+        };
+        merge_comments(v, &Value::Array(h.comments));
+        more.extend(h.more_ids);
+        cont.extend(h.continue_ids);
+    }
+    Ok(complete && comment_count(v) <= max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn with_after_appends_cursor() {
+        assert_eq!(with_after("https://old.reddit.com/r/rust/top/?t=week", "t3_abc"),
+                   "https://old.reddit.com/r/rust/top/?t=week&after=t3_abc");
+        assert_eq!(with_after("https://old.reddit.com/r/rust/new/", "t3_abc"),
+                   "https://old.reddit.com/r/rust/new/?after=t3_abc");
+    }
+
+    #[test]
+    fn with_after_replaces_existing_cursor() {
+        assert_eq!(with_after("https://old.reddit.com/r/rust/top/?t=day&after=t3_old&count=25", "t3_new"),
+                   "https://old.reddit.com/r/rust/top/?t=day&count=25&after=t3_new");
+    }
+
+    #[test]
+    fn with_after_leaves_unparsable_urls_alone() {
+        assert_eq!(with_after("not a url", "t3_abc"), "not a url");
+    }
+
+    fn depths(v: &Value) -> Vec<(String, i64)> {
+        v["comments"].as_array().unwrap().iter()
+            .map(|c| (c["id"].as_str().unwrap().to_string(), c["depth"].as_i64().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn chain_depths_rebases_continue_thread_pages() {
+        // c3 and c4 came from the "continue this thread" page focused on c3
+        let mut v = json!({"comments": [
+            {"id": "c1", "parent_fullname": "t3_p", "depth": 0},
+            {"id": "c2", "parent_fullname": "t1_c1", "depth": 1},
+            {"id": "c4", "parent_fullname": "t1_c3", "depth": 1},
+            {"id": "c3", "parent_fullname": "t1_c2", "depth": 0},
+        ]});
+        chain_depths(&mut v);
+        assert_eq!(depths(&v), vec![
+            ("c1".into(), 0), ("c2".into(), 1), ("c4".into(), 3), ("c3".into(), 2),
+        ]);
+    }
+
+    #[test]
+    fn chain_depths_keeps_depth_of_orphans() {
+        let mut v = json!({"comments": [
+            {"id": "c9", "parent_fullname": "t1_gone", "depth": 4},
+            {"id": "c10", "parent_fullname": "t1_c9", "depth": 0},
+        ]});
+        chain_depths(&mut v);
+        assert_eq!(depths(&v), vec![("c9".into(), 4), ("c10".into(), 5)]);
+    }
+
+    #[test]
+    fn chain_depths_stops_on_parent_cycles() {
+        let mut v = json!({"comments": [
+            {"id": "a", "parent_fullname": "t1_b", "depth": 2},
+            {"id": "b", "parent_fullname": "t1_a", "depth": 3},
+        ]});
+        chain_depths(&mut v);
+        assert_eq!(depths(&v), vec![("a".into(), 4), ("b".into(), 3)]);
+    }
+}
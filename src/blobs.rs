@@ -0,0 +1,70 @@
+use crate::cli::{Args, GcArgs};
+use crate::db::{content_hash, open_db, referenced_blobs};
+
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TMP_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// <dir>/ab/cd/abcd…: two levels of fan-out keep directories small
+pub fn blob_path(dir: &str, hash: &str) -> PathBuf {
+    Path::new(dir).join(&hash[0..2]).join(&hash[2..4]).join(hash)
+}
+
+// Writes the bytes under their SHA-256 unless already stored; returns the hash.
+pub fn store_blob(dir: &str, bytes: &[u8]) -> Result<String> {
+    let hash = content_hash(bytes);
+    let path = blob_path(dir, &hash);
+    if path.exists() { return Ok(hash); }
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    // write then rename so a crash never leaves a truncated blob under a valid name
+    // unique per process and call, since image workers can store the same bytes at once
+    let n = TMP_SEQ.fetch_add(1, Ordering::Relaxed);
+    let tmp = path.with_extension(format!("tmp{}-{n}", std::process::id()));
+    std::fs::write(&tmp, bytes)?;
+    if let Err(e) = std::fs::rename(&tmp, &path) {
+        let _ = std::fs::remove_file(&tmp);
+        // another writer got there first with the same content
+        if !path.exists() { return Err(e.into()); }
+    }
+    Ok(hash)
+}
+
+fn walk(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    for e in std::fs::read_dir(dir)? {
+        let p = e?.path();
+        if p.is_dir() { walk(&p, out)?; } else { out.push(p); }
+    }
+    Ok(())
+}
+
+// Deletes blobs that no media row references. Don't run it while a crawl is writing: a blob is
+// stored before its row is committed.
+pub fn run_gc(args: &Args, g: GcArgs) -> Result<()> {
+    let conn = open_db(&args.db)?;
+    let keep: HashSet<String> = referenced_blobs(&conn)?.into_iter().collect();
+    drop(conn);
+
+    let root = Path::new(&args.blob_dir);
+    if !root.exists() {
+        eprintln!("[GC] {} does not exist", args.blob_dir);
+        return Ok(());
+    }
+    let mut files = vec![];
+    walk(root, &mut files)?;
+
+    let (mut removed, mut freed) = (0usize, 0u64);
+    for f in files {
+        let name = f.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if keep.contains(name) { continue; }
+        let size = f.metadata().map(|m| m.len()).unwrap_or(0);
+        if !g.dry_run { std::fs::remove_file(&f)?; }
+        removed += 1;
+        freed += size;
+    }
+    let verb = if g.dry_run { "Would delete" } else { "Deleted" };
+    eprintln!("[GC] {verb} {removed} unreferenced blobs ({:.1} MB), {} referenced", freed as f64 / 1e6, keep.len());
+    Ok(())
+}
@@ -1,69 +1,334 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use serde::Serialize;
 
-#[derive(Parser, Debug, Clone)]
+#[derive(Parser, Debug, Clone, Serialize)]
 #[command(author, version, about = "Fast Reddit crawler (old.reddit + JS atomic extraction) with 429 safety")]
 pub struct Args {
 
-    #[arg(long)]
-    pub excel: String,
+    /// Defaults to crawl
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+
+    #[arg(long, global = true, help_heading = "Crawl")]
+    pub excel: Option<String>,
 
 
-    #[arg(long, default_value = "./reddit.duckdb")]
+    #[arg(long, default_value = "./reddit.duckdb", global = true, help_heading = "Database")]
     pub db: String,
 
 
-    #[arg(long, default_value = "old", value_parser = ["old"])]
+    #[arg(long, default_value = "old", value_parser = ["old","json"], global = true, help_heading = "Crawl")]
     pub mode: String,
 
 
-    #[arg(long, default_value_t = 20)]
+    #[arg(long, default_value = "top", value_parser = ["hot","new","rising","controversial","top"], global = true, help_heading = "Crawl")]
+    pub sort: String,
+
+
+    #[arg(long, default_value = "day", value_parser = ["hour","day","week","month","year","all"], global = true, help_heading = "Crawl")]
+    pub time: String,
+
+
+    #[arg(long, default_value_t = 20, global = true, help_heading = "Crawl")]
     pub max_pages: usize,
 
 
-    #[arg(long, default_value_t = true)]
+    #[arg(long, default_value_t = true, global = true, help_heading = "Crawl")]
     pub headless: bool,
 
 
-    #[arg(long, default_value_t = 0.8)]
+    #[arg(long, default_value_t = 0.8, global = true, help_heading = "Crawl")]
     pub delay: f64,
 
 
-    #[arg(long)]
+    #[arg(long, global = true, help_heading = "Crawl")]
     pub chrome_user_data_dir: Option<String>,
 
 
-    #[arg(long, default_value_t = 2)]
+    #[arg(long, default_value_t = 2, global = true, help_heading = "Crawl")]
     pub workers: usize,
 
 
-    #[arg(long, default_value_t = false)]
+    #[arg(long, default_value_t = false, global = true, help_heading = "Crawl")]
     pub use_uc: bool,
 
 
-    #[arg(long)]
+    #[arg(long, global = true, help_heading = "Crawl")]
     pub proxies_file: Option<String>,
 
 
-    #[arg(long, default_value_t = 24)]
+    #[arg(long, default_value_t = 24, global = true, help_heading = "Crawl")]
     pub rpm: u32,
 
 
-    #[arg(long, default_value_t = 3)]
+    #[arg(long, default_value_t = 3, global = true, help_heading = "Crawl")]
     pub polite_attempts: u32,
 
 
-    #[arg(long, default_value_t = 0.8)]
+    #[arg(long, default_value_t = 0.8, global = true, help_heading = "Crawl")]
     pub polite_base: f64,
 
 
-    #[arg(long, default_value_t = false)]
+    #[arg(long, default_value_t = false, global = true, help_heading = "Crawl")]
     pub verbose_429: bool,
 
 
-    #[arg(long, default_value = "base64", value_parser = ["base64","none"])]
+    /// base64 into the DB, blob files under --blob-dir (the DB keeps the hash), or none
+    #[arg(long, default_value = "base64", value_parser = ["base64","blob","none"], global = true, help_heading = "Images")]
     pub images: String,
 
 
-    #[arg(long, default_value_t = 500)]
+    #[arg(long, default_value = "./blobs", global = true, help_heading = "Images")]
+    pub blob_dir: String,
+
+
+    /// Concurrent image downloads, separate from the page workers
+    #[arg(long, default_value_t = 4, global = true, help_heading = "Images")]
+    pub image_workers: usize,
+
+
+    #[arg(long, default_value_t = 120, global = true, help_heading = "Images")]
+    pub image_rpm_per_host: u32,
+
+
+    #[arg(long, default_value_t = 20_000_000, global = true, help_heading = "Images")]
+    pub image_max_bytes: u64,
+
+
+    /// Comma-separated Content-Type allowlist
+    #[arg(long, default_value = "image/jpeg,image/png,image/gif,image/webp", global = true, help_heading = "Images")]
+    pub image_mimes: String,
+
+
+    #[arg(long, default_value_t = 3, global = true, help_heading = "Images")]
+    pub image_retries: u32,
+
+
+    #[arg(long, default_value_t = 30, global = true, help_heading = "Images")]
+    pub image_timeout: u64,
+
+
+    #[arg(long, default_value_t = 500, global = true, help_heading = "Crawl")]
     pub max_comments_per_post: usize,
+
+
+    #[arg(long, default_value_t = false, global = true, help_heading = "Crawl")]
+    pub expand_comments: bool,
+
+
+    /// Post bundles per DB transaction
+    #[arg(long, default_value_t = 50, global = true, help_heading = "Database")]
+    pub write_batch: usize,
+
+
+    /// Flush a partial batch after this many milliseconds
+    #[arg(long, default_value_t = 1000, global = true, help_heading = "Database")]
+    pub write_flush_ms: u64,
+
+
+    /// Messages buffered for the DB writer; page workers wait when it is full
+    #[arg(long, default_value_t = 256, global = true, help_heading = "Database")]
+    pub write_queue: usize,
+
+
+    /// NDJSON file for messages the DB writer could not store; load it back with `replay`
+    #[arg(long, default_value = "./dead_letter.ndjson", global = true, help_heading = "Database")]
+    pub dead_letter: String,
+
+
+    /// decay: weighted rates fading with post age; linear: the weighted rates as they are
+    #[arg(long, default_value = "decay", value_parser = ["decay","linear"], global = true, help_heading = "Metrics")]
+    pub virality_model: String,
+
+
+    /// Weights over score_vph, comments_vph, score_per_hour and comments_per_hour
+    #[arg(long, default_value = "score_vph=0.6,comments_vph=0.4", global = true, help_heading = "Metrics")]
+    pub virality_weights: String,
+
+
+    /// Post age at which the decay model halves the score
+    #[arg(long, default_value_t = 24.0, global = true, help_heading = "Metrics")]
+    pub half_life_hours: f64,
+
+
+    /// Earlier scans that join the current one in each subreddit's z-score baseline
+    #[arg(long, default_value_t = 5, global = true, help_heading = "Metrics")]
+    pub baseline_scans: usize,
+}
+
+#[derive(Subcommand, Debug, Clone, Serialize)]
+pub enum Command {
+    /// Crawl the subreddits listed in --excel (the default)
+    Crawl,
+
+    /// Recompute velocity metrics for one scan or all of them
+    Metrics(MetricsArgs),
+
+    /// Recompute post and comment metrics for every completed scan, e.g. after an upgrade
+    Backfill,
+
+    /// Summarize what is in the DB
+    Stats,
+
+    /// Delete old scans with their snapshots and metrics, and posts no remaining scan has seen
+    Prune(PruneArgs),
+
+    /// Re-snapshot posts already in the DB by fetching /comments/{id}/ directly
+    Refresh(RefreshArgs),
+
+    /// Keep running and rescan on an interval or cron schedule, reusing the browser sessions
+    Watch(WatchArgs),
+
+    /// Show the edit and deletion timeline of a post or comment
+    History(HistoryArgs),
+
+    /// Delete blobs under --blob-dir that no media row references anymore
+    Gc(GcArgs),
+
+    /// List visually near-identical images across posts and subreddits
+    Reposts(RepostsArgs),
+
+    /// Write the messages saved in a dead-letter file after a DB writer failure
+    Replay(ReplayArgs),
+
+    /// Write posts, comments, snapshots and metrics to Parquet, NDJSON or CSV files
+    Export(ExportArgs),
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct RefreshArgs {
+
+    /// Only posts created within the last N hours
+    #[arg(long)]
+    pub hours: Option<i64>,
+
+
+    /// Only posts whose last known score is at least this
+    #[arg(long)]
+    pub min_score: Option<i64>,
+
+
+    #[arg(long)]
+    pub subreddit: Option<String>,
+
+
+    #[arg(long, default_value_t = 500)]
+    pub limit: usize,
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct WatchArgs {
+
+    /// Default rescan interval; a subreddit's `interval` column (minutes) overrides it
+    #[arg(long, default_value_t = 60)]
+    pub interval_mins: u64,
+
+
+    /// Cron expression with seconds, e.g. "0 */30 * * * *"; replaces --interval-mins
+    #[arg(long)]
+    pub cron: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct HistoryArgs {
+
+    /// Post or comment id, with or without the t3_/t1_ prefix
+    pub id: String,
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct GcArgs {
+
+    /// Only report what would be deleted
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct RepostsArgs {
+
+    /// Max Hamming distance between 64-bit dHashes (0 = same picture)
+    #[arg(long, default_value_t = 6)]
+    pub threshold: i64,
+
+
+    /// Only pairs where at least one side is in this subreddit
+    #[arg(long)]
+    pub subreddit: Option<String>,
+
+
+    #[arg(long, default_value_t = 100)]
+    pub limit: usize,
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct ReplayArgs {
+
+    /// Defaults to --dead-letter
+    #[arg(long)]
+    pub file: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct ExportArgs {
+
+    /// Output directory, one sub-directory per table
+    #[arg(long, default_value = "./export")]
+    pub out: String,
+
+
+    /// parquet (partitioned, incremental), ndjson (one post per line with its comment tree) or csv (one file per table)
+    #[arg(long, default_value = "parquet", value_parser = ["parquet","ndjson","csv"])]
+    pub format: String,
+
+
+    /// Parquet only: rewrite --out from every scan instead of adding the scans since the last export
+    #[arg(long, default_value_t = false)]
+    pub full: bool,
+
+
+    #[arg(long)]
+    pub subreddit: Option<String>,
+
+
+    /// First scan id to include
+    #[arg(long)]
+    pub from_scan: Option<i64>,
+
+
+    /// Last scan id to include
+    #[arg(long)]
+    pub to_scan: Option<i64>,
+
+
+    /// Only posts, and their comments, snapshots and metrics, whose latest score is at least this
+    #[arg(long)]
+    pub min_score: Option<i64>,
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct MetricsArgs {
+
+    /// Scan id, or "all" to recompute every scan oldest first
+    #[arg(long)]
+    pub scan: String,
+}
+
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct PruneArgs {
+
+    /// Only scans older than this many days
+    #[arg(long)]
+    pub older_than_days: Option<i64>,
+
+
+    /// Never touch the newest N scans
+    #[arg(long)]
+    pub keep_scans: Option<usize>,
+
+
+    /// Only report what would be deleted
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
 }
@@ -1,14 +1,19 @@
-use crate::cli::Args;
-use crate::driver::make_driver;
-use crate::nav::{polite_get, PoliteKnobs};
-use crate::extract::{listing_old_top_day, post_old_page};
-use crate::throttle::Limiter;
+use crate::cli::{Args, RefreshArgs};
+use crate::backend::{Backend, BackendOpts};
+use crate::nav::PoliteKnobs;
+use crate::throttle::{take_rate_limited, Limiter};
 use crate::db::*;
 use crate::models::*;
-use crate::utils::fetch_image_b64;
+use crate::images::{ImageJob, ImagePool, ImagePoolOpts, ImageQueue};
+use crate::replay::DeadLetter;
+use crate::virality::Ranking;
 
-use crossbeam_channel::unbounded;
+use tokio::sync::mpsc;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle, ProgressDrawTarget};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 use anyhow::{Result, anyhow};
@@ -17,11 +22,13 @@ use rand::rngs::StdRng;
 use calamine::{open_workbook, Reader, Xlsx};
 use tokio::task::JoinSet;
 
-fn load_subreddits(xlsx_path: &str) -> Result<Vec<String>> {
+// Rows without a sort or time column take --sort / --time; the time only matters for top and
+// controversial, which may come from the row even when --sort has no window.
+fn load_subreddits(xlsx_path: &str, default_sort: &str, default_time: &str) -> Result<Vec<SubTarget>> {
     let mut wb: Xlsx<_> = open_workbook(xlsx_path)?;
     let first = wb
         .sheet_names()
-        .get(0)
+        .first()
         .ok_or_else(|| anyhow!("empty workbook"))?
         .to_string();
     let range = wb.worksheet_range(&first).ok_or_else(|| anyhow!("no sheet"))??;
@@ -35,83 +42,220 @@ fn load_subreddits(xlsx_path: &str) -> Result<Vec<String>> {
             header = Some(vals.iter().map(|s| s.trim().to_lowercase()).collect());
             continue;
         }
-        let sub = if let Some(h) = &header {
-            if let Some(idx) = h.iter().position(|c| c == "subreddit") {
-                vals.get(idx).map(|s| s.to_string())
-            } else {
-                vals.get(0).map(|s| s.to_string())
-            }
-        } else {
-            vals.get(0).map(|s| s.to_string())
+        let col = |names: &[&str]| -> Option<String> {
+            let h = header.as_ref()?;
+            let idx = h.iter().position(|c| names.contains(&c.as_str()))?;
+            vals.get(idx).map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
         };
+        let has_sub_col = header.as_ref().is_some_and(|h| h.iter().any(|c| c == "subreddit"));
+        let sub = if has_sub_col { col(&["subreddit"]) } else { vals.first().map(|s| s.to_string()) };
         if let Some(mut s) = sub {
             s = s.trim_start_matches('/').trim_start_matches("r/").to_string();
-            if !s.is_empty() {
-                res.push(s);
+            if s.is_empty() { continue; }
+
+            let sort = col(&["sort"]).unwrap_or_else(|| default_sort.to_string()).trim().to_lowercase();
+            let time = col(&["time", "t"]).unwrap_or_else(|| default_time.to_string()).trim().to_lowercase();
+            if !SORTS.contains(&sort.as_str()) {
+                return Err(anyhow!("r/{s}: unknown sort '{sort}' (expected one of {})", SORTS.join(", ")));
+            }
+            if !WINDOWS.contains(&time.as_str()) {
+                return Err(anyhow!("r/{s}: unknown time window '{time}' (expected one of {})", WINDOWS.join(", ")));
             }
+            let interval_mins = match col(&["interval", "interval_mins"]) {
+                Some(v) => Some(v.parse::<f64>().map_err(|_| anyhow!("r/{s}: bad interval '{v}' (minutes)"))? as u64),
+                None => None,
+            };
+            res.push(SubTarget { name: s, listing: Listing::new(&sort, &time), interval_mins });
         }
     }
     Ok(res)
 }
 
-#[derive(Debug)]
-enum Msg {
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PostBundle {
+    subreddit: String,
+    post: PostRow,
+    media: Vec<MediaRow>,
+    comments: Vec<CommentRow>,
+    tree_complete: bool,
+    snapshot: PostSnapshot,
+}
+
+// Also the line format of the dead-letter file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum Msg {
     BeginSubreddit(String),
-    PostBundle {
-        subreddit: String,
-        post: PostRow,
-        images: Vec<(String, Option<String>, Option<String>, Option<i64>)>, 
-        comments: Vec<CommentRow>,
-        snapshot: (String, i64, Option<i64>, Option<i64>, Option<i64>), 
-    },
-    Quit,
-}
-
-fn writer_thread(db_path: String, rx: crossbeam_channel::Receiver<Msg>) -> Result<()> {
-    let conn = open_db(&db_path)?;
-    let mut current_sub = String::new();
-    while let Ok(msg) = rx.recv() {
+    SubredditSnapshot(Box<SubredditSnapshot>),
+    PostBundle(Box<PostBundle>),
+    ImageFetched(Box<ImageFetch>),
+}
+
+impl Msg {
+    pub fn scan_id(&self) -> Option<i64> {
+        match self {
+            Msg::SubredditSnapshot(s) => Some(s.scan_id),
+            Msg::PostBundle(b) => Some(b.snapshot.scan_id),
+            _ => None,
+        }
+    }
+}
+
+pub struct WriteOpts {
+    pub batch: usize,
+    pub flush_ms: u64,
+}
+
+impl WriteOpts {
+    pub fn from_args(args: &Args) -> Self {
+        WriteOpts { batch: args.write_batch.max(1), flush_ms: args.write_flush_ms }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScanStats {
+    pub posts: usize,
+    pub comments: usize,
+    pub flushes: usize,
+    pub busy: Duration,
+    pub elapsed: Duration,
+    // filled in by the crawl once the writer is done
+    pub images: usize,
+    pub rate_limited: u64,
+    pub failures: usize,
+}
+
+impl ScanStats {
+    pub fn summary(&self) -> String {
+        let secs = self.elapsed.as_secs_f64().max(0.001);
+        format!(
+            "{} posts, {} comments, {} images in {:.1}s ({:.1} posts/s, {:.0} comments/s; {} flushes, {:.1}s writing; {} 429s, {} failures)",
+            self.posts, self.comments, self.images, secs, self.posts as f64 / secs, self.comments as f64 / secs,
+            self.flushes, self.busy.as_secs_f64(), self.rate_limited, self.failures
+        )
+    }
+}
+
+// A crawl that stopped early, with what it counted up to then, so the scan row still gets it.
+#[derive(Debug)]
+pub struct ScanAborted {
+    pub stats: ScanStats,
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Display for ScanAborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for ScanAborted {}
+
+// Runs on its own thread and borrows the runtime only to wait on the channel. On the first DB
+// error it closes the channel, so senders fail fast and stop, and spills the failed batch plus
+// everything still queued to the dead-letter file.
+fn writer_thread(
+    db_path: String, mut rx: mpsc::Receiver<Msg>, o: WriteOpts, rt: tokio::runtime::Handle, dl: DeadLetter,
+) -> Result<ScanStats> {
+    match write_loop(&db_path, &mut rx, &o, &rt) {
+        Ok(stats) => Ok(stats),
+        Err((e, unwritten)) => {
+            rx.close();
+            for m in &unwritten { dl.spill(m); }
+            while let Some(m) = rt.block_on(rx.recv()) { dl.spill(&m); }
+            Err(e)
+        }
+    }
+}
+
+// Buffers messages and writes them in one transaction every `batch` bundles or `flush_ms`,
+// whichever comes first. Messages keep their order, so image results land after their media rows.
+// On error, hands back the messages of the batch that was rolled back.
+fn write_loop(
+    db_path: &str, rx: &mut mpsc::Receiver<Msg>, o: &WriteOpts, rt: &tokio::runtime::Handle,
+) -> std::result::Result<ScanStats, (anyhow::Error, Vec<Msg>)> {
+    let conn = open_db(db_path).map_err(|e| (e, vec![]))?;
+    create_snapshot_stages(&conn).map_err(|e| (e, vec![]))?;
+    let started = Instant::now();
+    let mut stats = ScanStats::default();
+    let mut pending: Vec<Msg> = vec![];
+    let mut bundles = 0usize;
+    let mut oldest: Option<Instant> = None;
+
+    loop {
+        let wait = match oldest {
+            Some(t) => Duration::from_millis(o.flush_ms).saturating_sub(t.elapsed()),
+            None => Duration::from_secs(3600),
+        };
+        let next = rt.block_on(async { tokio::time::timeout(wait, rx.recv()).await });
+        let done = match next {
+            Ok(Some(msg)) => {
+                if matches!(msg, Msg::PostBundle(_)) { bundles += 1; }
+                oldest.get_or_insert_with(Instant::now);
+                pending.push(msg);
+                if bundles < o.batch { continue; }
+                false
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        };
+        if !pending.is_empty() {
+            let t = Instant::now();
+            let batch = std::mem::take(&mut pending);
+            let mut written = (0, 0);
+            if let Err(e) = in_tx(&conn, |c| { written = write_batch(c, &batch)?; Ok(()) }) {
+                return Err((e, batch));
+            }
+            stats.posts += written.0;
+            stats.comments += written.1;
+            stats.flushes += 1;
+            stats.busy += t.elapsed();
+        }
+        bundles = 0;
+        oldest = None;
+        if done { break; }
+    }
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+// (posts, comments) written
+fn write_batch(conn: &duckdb::Connection, msgs: &[Msg]) -> Result<(usize, usize)> {
+    let mut snaps = SnapshotBatch::default();
+    let (mut posts, mut comments) = (0, 0);
+    for msg in msgs {
         match msg {
             Msg::BeginSubreddit(s) => {
-                current_sub = s;
-                let _ = upsert_subreddit(&conn, &current_sub);
+                upsert_subreddit(conn, s)?;
             }
-            Msg::PostBundle { subreddit, post, images, comments, snapshot } => {
-                let sub_id = upsert_subreddit(&conn, &subreddit)?;
-                upsert_post(
-                    &conn,
-                    &post.id,
-                    sub_id,
-                    &post.url,
-                    post.title.as_deref(),
-                    post.author.as_deref(),
-                    post.score,
-                    post.created_utc,
-                    post.selftext.as_deref(),
-                    post.num_comments,
-                )?;
-                for (u, b64, mime, size) in images {
-                    ensure_image(&conn, &post.id, &u, b64.as_deref(), mime.as_deref(), size)?;
-                }
-                for c in comments {
-                    upsert_comment(
-                        &conn,
-                        &c.id,
-                        &c.post_id,
-                        c.parent_fullname.as_deref(),
-                        c.author.as_deref(),
-                        c.body.as_deref(),
-                        c.score,
-                        c.created_utc,
-                    )?;
-                    snapshot_comment(&conn, &c.id, snapshot.1, c.score, c.created_utc)?;
+            Msg::SubredditSnapshot(s) => {
+                let sub_id = upsert_subreddit(conn, &s.subreddit)?;
+                snaps.subreddits.push((sub_id, (**s).clone()));
+            }
+            Msg::PostBundle(b) => {
+                let PostBundle { subreddit, post, media, comments: rows, tree_complete, snapshot } = &**b;
+                let sub_id = upsert_subreddit(conn, subreddit)?;
+                upsert_post(conn, sub_id, post, snapshot.scan_id)?;
+                replace_media(conn, &post.id, media)?;
+                for c in rows {
+                    upsert_comment(conn, c, snapshot.scan_id)?;
+                    snaps.comments.push(CommentSnapshot {
+                        comment_id: c.id.clone(), scan_id: snapshot.scan_id, score: c.score, created_utc: c.created_utc,
+                    });
                 }
-                snapshot_post(&conn, &post.id, snapshot.1, snapshot.2, snapshot.3, snapshot.4)?;
+                mark_comment_tree(conn, &post.id, *tree_complete)?;
+                snaps.posts.push(snapshot.clone());
+                posts += 1;
+                comments += rows.len();
+            }
+            Msg::ImageFetched(f) => {
+                if let Some(e) = &f.error { eprintln!("[image] {}: {e}", f.url); }
+                record_image_fetch(conn, f)?;
             }
-            Msg::Quit => break,
         }
     }
-    Ok(())
+    write_snapshots(conn, &snaps)?;
+    Ok((posts, comments))
 }
 
 fn session_gone<E: std::fmt::Display>(e: &E) -> bool {
@@ -130,97 +274,428 @@ fn ui_set(pb: &ProgressBar, last: &mut Instant, msg: String) {
     }
 }
 
-pub async fn run_crawl(args: Args, limiter: Limiter, knobs: PoliteKnobs, scan_id: i64) -> Result<usize> {
+struct BundleOpts {
+    scan_id: i64,
+    max_comments: usize,
+}
+
+// Turns the page value from either backend into the rows the writer stores.
+fn build_bundle(v: Value, item: ListingItem, sub: &str, listing: &Listing, o: &BundleOpts) -> PostBundle {
+    let (post_id, href_opt, ts_opt) = item;
+    let post_url = format!("https://old.reddit.com/comments/{}/", post_id);
+
+    let title   = v.get("title").and_then(|x| x.as_str()).map(|s| s.to_string());
+    let author  = v.get("author").and_then(|x| x.as_str()).map(|s| s.to_string());
+    let score   = v.get("score").and_then(|x| x.as_i64());
+    let created = v.get("created_utc").and_then(|x| x.as_i64()).or(ts_opt);
+    let body    = v.get("selftext").and_then(|x| x.as_str()).map(|s| s.to_string());
+    let ncom    = v.get("num_comments").and_then(|x| x.as_i64());
+    let text    = |k: &str| v.get(k).and_then(|x| x.as_str()).map(|s| s.to_string());
+    let flag    = |k: &str| v.get(k).and_then(|x| x.as_bool());
+
+    let media_out: Vec<MediaRow> = v.get("media").and_then(|x| x.as_array()).cloned().unwrap_or_default()
+        .iter()
+        .filter_map(|m| {
+            let url = m.get("url").and_then(|x| x.as_str())?.to_string();
+            let text = |k: &str| m.get(k).and_then(|x| x.as_str()).map(|s| s.to_string());
+            Some(MediaRow {
+                position: m.get("position").and_then(|x| x.as_i64()).unwrap_or(0),
+                kind: text("kind").unwrap_or_else(|| "image".to_string()),
+                url,
+                caption: text("caption"),
+                mime: text("mime"),
+                width: m.get("width").and_then(|x| x.as_i64()),
+                height: m.get("height").and_then(|x| x.as_i64()),
+                duration_secs: m.get("duration").and_then(|x| x.as_f64()),
+                fallback_url: text("fallback_url"),
+            })
+        })
+        .collect();
+
+    let mut comments_out = vec![];
+    let comments = v.get("comments").and_then(|x| x.as_array()).cloned().unwrap_or_default();
+    let tree_complete = v.get("tree_complete").and_then(|x| x.as_bool()).unwrap_or(false)
+        && comments.len() <= o.max_comments;
+    for c in comments.into_iter().take(o.max_comments) {
+        let cid   = c.get("id").and_then(|x| x.as_str()).unwrap_or("").to_string();
+        if cid.is_empty() { continue; }
+        let cauth = c.get("author").and_then(|x| x.as_str()).map(|s| s.to_string());
+        let cbody = c.get("body").and_then(|x| x.as_str()).map(|s| s.to_string());
+        let csc   = c.get("score").and_then(|x| x.as_i64());
+        let cts   = c.get("created_utc").and_then(|x| x.as_i64());
+        let par   = c.get("parent_fullname").and_then(|x| x.as_str()).map(|s| s.to_string());
+        comments_out.push(CommentRow {
+            id: cid, post_id: post_id.clone(),
+            parent_fullname: par, author: cauth.clone(), body: cbody.clone(),
+            score: csc, created_utc: cts,
+            depth: c.get("depth").and_then(|x| x.as_i64()),
+            permalink: c.get("permalink").and_then(|x| x.as_str()).map(|s| s.to_string()),
+            edited: c.get("edited").and_then(|x| x.as_bool()),
+            edited_utc: c.get("edited_utc").and_then(|x| x.as_i64()),
+            distinguished: c.get("distinguished").and_then(|x| x.as_str()).map(|s| s.to_string()),
+            stickied: c.get("stickied").and_then(|x| x.as_bool()),
+            score_hidden: c.get("score_hidden").and_then(|x| x.as_bool()),
+            state: content_state(cauth.as_deref(), cbody.as_deref(), None),
+        });
+    }
+
+    let state = content_state(author.as_deref(), body.as_deref(), v.get("removed_by_category").and_then(|x| x.as_str()));
+    let row = PostRow {
+        id: post_id.clone(),
+        url: href_opt.unwrap_or(post_url),
+        title, author, score, created_utc: created, selftext: body, num_comments: ncom,
+        flair: text("flair"),
+        over_18: flag("over_18"),
+        spoiler: flag("spoiler"),
+        stickied: flag("stickied"),
+        locked: flag("locked"),
+        domain: text("domain"),
+        crosspost_parent: text("crosspost_parent"),
+        gilded: v.get("gilded").and_then(|x| x.as_i64()),
+        outbound_url: text("url"),
+        upvote_ratio: v.get("upvote_ratio").and_then(|x| x.as_f64()),
+        state,
+    };
+    let snap = PostSnapshot {
+        post_id,
+        scan_id: o.scan_id,
+        score,
+        num_comments: ncom,
+        created_utc: created,
+        listing: listing.clone(),
+        upvote_ratio: row.upvote_ratio,
+        flair: row.flair.clone(),
+        over_18: row.over_18,
+        spoiler: row.spoiler,
+        stickied: row.stickied,
+        locked: row.locked,
+        gilded: row.gilded,
+    };
+    PostBundle {
+        subreddit: sub.to_string(),
+        post: row,
+        media: media_out,
+        comments: comments_out,
+        tree_complete,
+        snapshot: snap,
+    }
+}
+
+fn subreddit_snapshot(v: &Value, sub: &str, scan_id: i64) -> SubredditSnapshot {
+    let list = |k: &str| v.get(k).filter(|x| x.is_array()).map(|x| x.to_string());
+    SubredditSnapshot {
+        subreddit: sub.to_string(),
+        scan_id,
+        subscribers: v.get("subscribers").and_then(|x| x.as_i64()),
+        users_online: v.get("users_online").and_then(|x| x.as_i64()),
+        created_utc: v.get("created_utc").and_then(|x| x.as_i64()),
+        description: v.get("description").and_then(|x| x.as_str()).map(|s| s.to_string()),
+        rules: list("rules"),
+        moderators: list("moderators"),
+    }
+}
+
+// False once the writer is gone; the message then goes to the dead-letter file instead.
+pub async fn send_msg(tx: &mpsc::Sender<Msg>, dl: &DeadLetter, msg: Msg) -> bool {
+    match tx.send(msg).await {
+        Ok(()) => true,
+        Err(e) => { dl.spill(&e.0); false }
+    }
+}
+
+// Hands the rows to the writer first so the image results always find their media row.
+async fn send_bundle(
+    tx: &mpsc::Sender<Msg>, dl: &DeadLetter, images: &ImageQueue, b: PostBundle, proxy: Option<&str>,
+) -> bool {
+    let jobs: Vec<ImageJob> = b.media.iter().filter(|m| m.is_image())
+        .map(|m| ImageJob {
+            post_id: b.post.id.clone(), position: m.position, url: m.url.clone(),
+            proxy: proxy.map(|p| p.to_string()),
+        })
+        .collect();
+    if !send_msg(tx, dl, Msg::PostBundle(Box::new(b))).await { return false; }
+    for j in jobs { images.submit(j).await; }
+    true
+}
+
+// A full queue makes `send` wait, which stalls the page workers instead of buffering without bound.
+pub fn start_writer(args: &Args, dl: &DeadLetter) -> (mpsc::Sender<Msg>, std::thread::JoinHandle<Result<ScanStats>>) {
+    let (tx, rx) = mpsc::channel::<Msg>(args.write_queue.max(1));
+    let db_path = args.db.clone();
+    let wo = WriteOpts::from_args(args);
+    let rt = tokio::runtime::Handle::current();
+    let dl = dl.clone();
+    let wt = std::thread::spawn(move || writer_thread(db_path, rx, wo, rt, dl));
+    (tx, wt)
+}
+
+// Call once every sender is dropped.
+pub fn finish_writer(wt: std::thread::JoinHandle<Result<ScanStats>>, dl: &DeadLetter) -> Result<ScanStats> {
+    match wt.join() {
+        Ok(Ok(stats)) => Ok(stats),
+        Ok(Err(e)) => Err(anyhow!(
+            "DB writer failed: {e}; {} unwritten messages saved to {} (load them with `replay`)", dl.count(), dl.path()
+        )),
+        Err(_) => Err(anyhow!("DB writer thread panicked")),
+    }
+}
+
+// Shows the writer's backlog on the overall bar until every sender is gone.
+fn show_queue_depth(bar: &ProgressBar, tx: &mpsc::Sender<Msg>) {
+    let bar = bar.clone();
+    let weak = tx.downgrade();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            let Some(tx) = weak.upgrade() else { break };
+            let depth = tx.max_capacity() - tx.capacity();
+            bar.set_message(format!("• writer queue {depth}/{}", tx.max_capacity()));
+        }
+    });
+}
+
+fn start_image_pool(args: &Args, tx: &mpsc::Sender<Msg>, dl: &DeadLetter) -> ImagePool {
+    let txc = tx.clone();
+    let dl = dl.clone();
+    ImagePool::start(ImagePoolOpts::from_args(args), move |f| {
+        let (txc, dl) = (txc.clone(), dl.clone());
+        async move { send_msg(&txc, &dl, Msg::ImageFetched(Box::new(f))).await }
+    })
+}
+
+async fn finish_image_pool(pool: ImagePool) -> (usize, usize) {
+    let (ok, failed) = pool.finish().await;
+    if ok + failed > 0 {
+        eprintln!("[IMAGES] {ok} downloaded, {failed} failed (see media.fetch_error)");
+    }
+    (ok, failed)
+}
 
-    let subs = load_subreddits(&args.excel)?;
-    if subs.is_empty() { return Err(anyhow!("No subreddits in {}", args.excel)); }
+// Waits for the image pool and the writer and adds the crawl's own counts to the writer's. If the
+// writer failed, or nothing was fetched because every request failed (network down, blocked), the
+// counts travel with the error as a `ScanAborted`, so metrics never treat the scan as valid.
+async fn finish_scan_stats(
+    pool: ImagePool, wt: std::thread::JoinHandle<Result<ScanStats>>, dl: &DeadLetter, failures: &AtomicUsize,
+) -> Result<ScanStats> {
+    let (images, image_failures) = finish_image_pool(pool).await;
+    let res = finish_writer(wt, dl);
+    let (mut stats, error) = match res {
+        Ok(s) => (s, None),
+        Err(e) => (ScanStats::default(), Some(e)),
+    };
+    stats.images = images;
+    stats.failures = failures.load(Ordering::Relaxed) + image_failures;
+    stats.rate_limited = take_rate_limited();
+    let error = error.or_else(|| (stats.posts == 0 && stats.failures > 0)
+        .then(|| anyhow!("nothing fetched: no posts and {} failures", stats.failures)));
+    match error {
+        None => Ok(stats),
+        Some(error) => Err(ScanAborted { stats, error }.into()),
+    }
+}
 
+fn worker_proxy(proxies: &[String], w: usize) -> Option<String> {
+    if proxies.is_empty() { None } else { Some(proxies[w % proxies.len()].clone()) }
+}
 
-    let proxies: Vec<String> = if let Some(p) = &args.proxies_file {
+fn load_proxies(args: &Args) -> Vec<String> {
+    if let Some(p) = &args.proxies_file {
         let t = std::fs::read_to_string(p).unwrap_or_default();
         t.lines().map(|l| l.trim())
             .filter(|s| !s.is_empty() && !s.starts_with('#'))
             .map(|s| s.to_string()).collect()
-    } else { vec![] };
-
+    } else { vec![] }
+}
 
-    let (tx, rx) = unbounded::<Msg>();
-    let db_path = args.db.clone();
-    let wt = std::thread::spawn(move || writer_thread(db_path, rx).expect("writer thread failed"));
+async fn open_worker_backend(args: &Args, w: usize, proxies: &[String]) -> Result<Backend> {
+    let webdriver_url = std::env::var("WEBDRIVER_URL")
+        .unwrap_or_else(|_| "http://127.0.0.1:9515".to_string());
+
+    let user_data_dir_w = args.chrome_user_data_dir.as_ref().map(|base| {
+        let p = std::path::Path::new(base).join(format!("worker-{}", w));
+        let _ = std::fs::create_dir_all(&p);
+        p.to_string_lossy().to_string()
+    });
+
+    let proxy = worker_proxy(proxies, w);
+
+    Backend::open(BackendOpts {
+        mode: &args.mode,
+        headless: args.headless,
+        user_data_dir: user_data_dir_w.as_deref(),
+        proxy: proxy.as_deref(),
+        worker_id: w,
+        webdriver_url: &webdriver_url,
+    }).await
+}
 
+fn worker_bar(mp: &MultiProgress, w: usize) -> Result<ProgressBar> {
+    let wbar = mp.add(ProgressBar::new(0));
+    wbar.set_style(
+        ProgressStyle::with_template("w{prefix}: {wide_msg}")?
+            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
+    );
+    wbar.set_prefix(format!("{w}"));
+    wbar.enable_steady_tick(Duration::from_millis(120));
+    Ok(wbar)
+}
 
-    let mp = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
-    let overall = mp.add(ProgressBar::new(subs.len() as u64));
+fn overall_bar(mp: &MultiProgress, len: usize, what: &str) -> Result<ProgressBar> {
+    let overall = mp.add(ProgressBar::new(len as u64));
     overall.set_style(
-        ProgressStyle::with_template("{spinner:.green} {pos}/{len} subs done")?
+        ProgressStyle::with_template(&format!("{{spinner:.green}} {{pos}}/{{len}} {what} done {{msg}}"))?
             .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
     );
     overall.enable_steady_tick(Duration::from_millis(120));
+    Ok(overall)
+}
+
+async fn jitter_sleep(delay: f64) {
+    tokio::time::sleep(std::time::Duration::from_millis(
+        (delay_ms(delay) as f64 * (0.6 + rand::random::<f64>() * 0.8)) as u64
+    )).await;
+}
+
+pub fn load_targets(args: &Args) -> Result<Vec<SubTarget>> {
+    let excel = args.excel.clone().ok_or_else(|| anyhow!("--excel is required to crawl"))?;
+    let subs = load_subreddits(&excel, &args.sort, &args.time)?;
+    if subs.is_empty() { return Err(anyhow!("No subreddits in {}", excel)); }
+    Ok(subs)
+}
 
+// One slot per worker id; None when the backend failed to start or was lost.
+pub type Workers = Vec<Option<Backend>>;
+
+pub async fn open_workers(args: &Args, n: usize) -> Workers {
+    let proxies = load_proxies(args);
+    let opening = (0..n).map(|w| open_worker_backend(args, w, &proxies));
+    futures::future::join_all(opening).await.into_iter().enumerate()
+        .map(|(w, r)| match r {
+            Ok(b) => Some(b),
+            Err(e) => { eprintln!("[worker {w}] start backend error: {e}"); None }
+        })
+        .collect()
+}
 
-    let mut order = subs.clone();
+// Restarts any worker whose session died since the last scan.
+pub async fn revive_workers(args: &Args, workers: &mut Workers) {
+    let proxies = load_proxies(args);
+    for (w, slot) in workers.iter_mut().enumerate() {
+        let alive = match slot {
+            Some(b) => b.alive().await,
+            None => false,
+        };
+        if alive { continue; }
+        if let Some(dead) = slot.take() { dead.quit().await; }
+        match open_worker_backend(args, w, &proxies).await {
+            Ok(b) => *slot = Some(b),
+            Err(e) => eprintln!("[worker {w}] restart backend error: {e}"),
+        }
+    }
+}
+
+pub async fn close_workers(workers: Workers) {
+    for b in workers.into_iter().flatten() {
+        b.quit().await;
+    }
+}
+
+// Records how the scan ended and computes metrics for it if it completed.
+pub fn close_scan(db: &str, scan_id: i64, ranking: &Ranking, res: Result<ScanStats>) -> Result<ScanStats> {
+    let conn = open_db(db)?;
+    match res {
+        Ok(stats) => {
+            finish_scan(&conn, scan_id, &stats, None)?;
+            compute_metrics(&conn, scan_id, ranking)?;
+            Ok(stats)
+        }
+        Err(e) => {
+            let stats = match e.downcast_ref::<ScanAborted>() {
+                Some(a) => a.stats,
+                None => ScanStats { rate_limited: take_rate_limited(), ..Default::default() },
+            };
+            finish_scan(&conn, scan_id, &stats, Some(&format!("{e:#}")))?;
+            Err(e)
+        }
+    }
+}
+
+pub async fn run_crawl(args: Args, limiter: Limiter, knobs: PoliteKnobs, scan_id: i64) -> Result<ScanStats> {
+    let subs = load_targets(&args)?;
+    let workers = open_workers(&args, args.workers.min(subs.len())).await;
+    let (stats, workers) = crawl_with(&args, subs, workers, limiter, knobs, scan_id).await?;
+    close_workers(workers).await;
+    Ok(stats)
+}
+
+// Crawls `subs` with already-open backends and hands them back afterwards, so a long-running
+// caller can keep sessions warm between scans.
+pub async fn crawl_with(
+    args: &Args, subs: Vec<SubTarget>, workers: Workers, limiter: Limiter, knobs: PoliteKnobs, scan_id: i64,
+) -> Result<(ScanStats, Workers)> {
+
+    let mut slots: Workers = (0..workers.len()).map(|_| None).collect();
+    let live: Vec<(usize, Backend)> = workers.into_iter().enumerate()
+        .filter_map(|(w, b)| b.map(|b| (w, b)))
+        .collect();
+    if live.is_empty() { return Err(anyhow!("no worker backend could be started")); }
+    let proxies = load_proxies(args);
+
+
+    let dl = DeadLetter::new(&args.dead_letter);
+    let (tx, wt) = start_writer(args, &dl);
+    let pool = start_image_pool(args, &tx, &dl);
+    let failures = Arc::new(AtomicUsize::new(0));
+    take_rate_limited();
+
+
+    let mp = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+    let overall = overall_bar(&mp, subs.len(), "subs")?;
+    show_queue_depth(&overall, &tx);
+
+
+    let mut order = subs;
     let mut rng = StdRng::seed_from_u64(42);
     order.shuffle(&mut rng);
 
     let mut js = JoinSet::new();
-    let per_worker = (order.len() + args.workers - 1) / args.workers;
+    let per_worker = order.len().div_ceil(live.len());
 
-    for w in 0..args.workers {
-        let slice = order.clone().into_iter().skip(w * per_worker).take(per_worker).collect::<Vec<_>>();
-        if slice.is_empty() { continue; }
+    for (n, (w, backend)) in live.into_iter().enumerate() {
+        let slice = order.iter().skip(n * per_worker).take(per_worker).cloned().collect::<Vec<_>>();
+        if slice.is_empty() {
+            slots[w] = Some(backend);
+            continue;
+        }
 
-        let wbar = mp.add(ProgressBar::new(0));
-        wbar.set_style(
-            ProgressStyle::with_template("w{prefix}: {wide_msg}")?
-                .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏")
-        );
-        wbar.set_prefix(format!("{w}"));
-        wbar.enable_steady_tick(Duration::from_millis(120));
+        let wbar = worker_bar(&mp, w)?;
 
         let txc           = tx.clone();
+        let dl_c          = dl.clone();
+        let failures_c    = failures.clone();
+        let images        = pool.queue();
+        let proxy         = worker_proxy(&proxies, w);
         let limiter_c     = limiter.clone();
         let knobs_c       = knobs;
-        let headless      = args.headless;
         let delay         = args.delay;
         let max_pages     = args.max_pages;
-        let images_mode   = args.images.clone();
-        let max_comments  = args.max_comments_per_post;
-
-
-        let user_data_dir_w = args.chrome_user_data_dir.as_ref().map(|base| {
-            let p = std::path::Path::new(base).join(format!("worker-{}", w));
-            let _ = std::fs::create_dir_all(&p);
-            p.to_string_lossy().to_string()
-        });
-
-        
-        let proxy = if proxies.is_empty() { None } else { Some(proxies[w % proxies.len()].clone()) };
+        let expand        = args.expand_comments.then_some(args.max_comments_per_post);
+        let opts = BundleOpts {
+            scan_id,
+            max_comments: args.max_comments_per_post,
+        };
         let overall_c = overall.clone();
 
         js.spawn(async move {
-            let webdriver_url = std::env::var("WEBDRIVER_URL")
-                .unwrap_or_else(|_| "http://127.0.0.1:9515".to_string());
-
-            let drv = match make_driver(
-                headless,
-                user_data_dir_w.as_deref(),
-                None, 
-                proxy.as_deref(),
-                w,
-                &webdriver_url
-            ).await {
-                Ok(drv) => drv,
-                Err(e) => { eprintln!("[worker {w}] start driver error: {e}"); wbar.finish_with_message("failed to start"); return 0usize; }
-            };
-
-            let mut saved = 0usize;
             let mut last_ui = Instant::now();
 
-            'sub_loop: for sub in slice {
-                ui_set(&wbar, &mut last_ui, format!("r/{sub} — page 1/{max_pages}"));
-                let _ = txc.send(Msg::BeginSubreddit(sub.clone()));
+            'sub_loop: for target in slice {
+                let sub = target.name.clone();
+                let listing = target.listing.clone();
+                ui_set(&wbar, &mut last_ui, format!("r/{sub} [{}] — page 1/{max_pages}", listing.label()));
+                if !send_msg(&txc, &dl_c, Msg::BeginSubreddit(sub.clone())).await { break 'sub_loop; }
 
-                let base = format!("https://old.reddit.com/r/{}/top/?t=day", sub);
+                let base = listing.url(&sub);
                 let mut next = Some(base);
                 let mut pages = 0usize;
 
@@ -228,111 +703,58 @@ pub async fn run_crawl(args: Args, limiter: Limiter, knobs: PoliteKnobs, scan_id
                     if pages >= max_pages { break; }
                     ui_set(&wbar, &mut last_ui, format!("r/{sub} — page {}/{}", pages + 1, max_pages));
 
-                    if !polite_get(&drv, &limiter_c, &url, knobs_c).await.unwrap_or(false) { break; }
-
-                    match listing_old_top_day(&drv).await {
+                    let next_href = match backend.listing(&limiter_c, &url, knobs_c).await {
+                        Ok(None) => { failures_c.fetch_add(1, Ordering::Relaxed); break }
                         Err(e) => {
+                            failures_c.fetch_add(1, Ordering::Relaxed);
                             if session_gone(&e) { eprintln!("[w{w}] session lost on listing: {e}"); break 'sub_loop; }
                             eprintln!("[{sub}] listing parse error: {e}");
                             break;
                         }
-                        Ok(listing) => {
-                            let total_on_page = listing.len().max(1);
-                            for (idx, (post_id, href_opt, ts_opt)) in listing.into_iter().enumerate() {
+                        Ok(Some((items, next_href))) => {
+                            if pages == 0 {
+                                match backend.sidebar(&limiter_c, &sub, knobs_c).await {
+                                    Ok(Some(v)) => {
+                                        let snap = subreddit_snapshot(&v, &sub, scan_id);
+                                        if !send_msg(&txc, &dl_c, Msg::SubredditSnapshot(Box::new(snap))).await { break 'sub_loop; }
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        failures_c.fetch_add(1, Ordering::Relaxed);
+                                        eprintln!("[{sub}] sidebar parse error: {e}");
+                                    }
+                                }
+                            }
+                            let total_on_page = items.len().max(1);
+                            for (idx, item) in items.into_iter().enumerate() {
                                 ui_set(
                                     &wbar, &mut last_ui,
                                     format!("r/{sub} — page {}/{} • post {}/{}",
                                             pages + 1, max_pages, idx + 1, total_on_page)
                                 );
 
-                                let post_url = format!("https://old.reddit.com/comments/{}/", post_id);
-                                if !polite_get(&drv, &limiter_c, &post_url, knobs_c).await.unwrap_or(false) {
-                                    if drv.current_url().await.is_err() { break 'sub_loop; }
-                                    continue;
-                                }
-
-                                match post_old_page(&drv).await {
-                                    Ok(v) => {
-                                        let title   = v.get("title").and_then(|x| x.as_str()).map(|s| s.to_string());
-                                        let author  = v.get("author").and_then(|x| x.as_str()).map(|s| s.to_string());
-                                        let score   = v.get("score").and_then(|x| x.as_i64());
-                                        let created = v.get("created_utc").and_then(|x| x.as_i64()).or(ts_opt);
-                                        let body    = v.get("selftext").and_then(|x| x.as_str()).map(|s| s.to_string());
-                                        let ncom    = v.get("num_comments").and_then(|x| x.as_i64());
-
-                                        let mut images_out = vec![];
-                                        let imgs = v.get("images").and_then(|x| x.as_array()).cloned().unwrap_or_default();
-                                        if images_mode == "base64" && !imgs.is_empty() {
-                                            let client = reqwest::Client::builder().build().unwrap();
-                                            for u in imgs.iter().filter_map(|x| x.as_str()) {
-                                                if let Ok((b64, mime, size)) = fetch_image_b64(&client, u).await {
-                                                    images_out.push((u.to_string(), b64, mime, size));
-                                                } else {
-                                                    images_out.push((u.to_string(), None, None, None));
-                                                }
-                                            }
-                                        } else {
-                                            for u in imgs.iter().filter_map(|x| x.as_str()) {
-                                                images_out.push((u.to_string(), None, None, None));
-                                            }
-                                        }
-
-                                        let mut comments_out = vec![];
-                                        let comments = v.get("comments").and_then(|x| x.as_array()).cloned().unwrap_or_default();
-                                        for c in comments.into_iter().take(max_comments) {
-                                            let cid   = c.get("id").and_then(|x| x.as_str()).unwrap_or("").to_string();
-                                            if cid.is_empty() { continue; }
-                                            let cauth = c.get("author").and_then(|x| x.as_str()).map(|s| s.to_string());
-                                            let cbody = c.get("body").and_then(|x| x.as_str()).map(|s| s.to_string());
-                                            let csc   = c.get("score").and_then(|x| x.as_i64());
-                                            let cts   = c.get("created_utc").and_then(|x| x.as_i64());
-                                            let par   = c.get("parent_fullname").and_then(|x| x.as_str()).map(|s| s.to_string());
-                                            comments_out.push(CommentRow {
-                                                id: cid, post_id: post_id.clone(),
-                                                parent_fullname: par, author: cauth, body: cbody,
-                                                score: csc, created_utc: cts
-                                            });
-                                        }
-
-                                        let row = PostRow {
-                                            id: post_id.clone(),
-                                            url: href_opt.unwrap_or(post_url.clone()),
-                                            title, author, score, created_utc: created, selftext: body, num_comments: ncom,
-                                        };
-                                        let snap = (post_id.clone(), scan_id, score, ncom, created);
-                                        let _ = txc.send(Msg::PostBundle {
-                                            subreddit: sub.clone(),
-                                            post: row,
-                                            images: images_out,
-                                            comments: comments_out,
-                                            snapshot: snap,
-                                        });
-
-                                        saved += 1;
-                                        tokio::time::sleep(std::time::Duration::from_millis(
-                                            (delay_ms(delay) as f64 * (0.6 + rand::random::<f64>() * 0.8)) as u64
-                                        )).await;
+                                let post_id = item.0.clone();
+                                match backend.post(&limiter_c, &post_id, knobs_c, expand).await {
+                                    Ok(None) => {
+                                        failures_c.fetch_add(1, Ordering::Relaxed);
+                                        if !backend.alive().await { break 'sub_loop; }
+                                        continue;
+                                    }
+                                    Ok(Some(v)) => {
+                                        let bundle = build_bundle(v, item, &sub, &listing, &opts);
+                                        if !send_bundle(&txc, &dl_c, &images, bundle, proxy.as_deref()).await { break 'sub_loop; }
+                                        jitter_sleep(delay).await;
                                     }
                                     Err(e) => {
+                                        failures_c.fetch_add(1, Ordering::Relaxed);
                                         if session_gone(&e) { eprintln!("[w{w}] session lost on post: {e}"); break 'sub_loop; }
                                         eprintln!("[{sub}] post {post_id} parse error: {e}");
                                     }
                                 }
                             }
+                            next_href
                         }
-                    }
-
-
-                    let mut next_href = drv.find_all(thirtyfour::By::Css("span.next-button > a")).await
-                        .ok().and_then(|mut v| v.pop())
-                        .and_then(|e| futures::executor::block_on(e.attr("href")).ok().flatten());
-
-                    if next_href.is_none() {
-                        next_href = drv.execute("return (document.querySelector('span.next-button > a')||{}).href;", vec![])
-                            .await.ok()
-                            .and_then(|ret| ret.convert::<Option<String>>().ok())
-                            .flatten();
-                    }
+                    };
 
                     next = next_href;
                     pages += 1; 
@@ -342,26 +764,131 @@ pub async fn run_crawl(args: Args, limiter: Limiter, knobs: PoliteKnobs, scan_id
                 ui_set(&wbar, &mut last_ui, format!("r/{sub} — done"));
             }
 
-            let _ = drv.quit().await; 
             wbar.finish_and_clear();
-            saved
+            (w, backend)
         });
     }
 
     drop(tx);
 
 
-    let mut total_saved = 0usize;
     while let Some(res) = js.join_next().await {
-        if let Ok(n) = res { total_saved += n; }
+        if let Ok((w, backend)) = res {
+            slots[w] = Some(backend);
+        }
     }
 
     overall.finish_and_clear();
     let _ = mp.clear();
 
-    wt.join().ok();
+    let stats = match finish_scan_stats(pool, wt, &dl, &failures).await {
+        Ok(s) => s,
+        Err(e) => { close_workers(slots).await; return Err(e); }
+    };
+
+    Ok((stats, slots))
+}
+
+// Re-fetches known posts straight from /comments/{id}/ so they get a fresh snapshot even when
+// they have dropped out of the listings. Snapshots are tagged with the "refresh" listing.
+pub async fn run_refresh(args: Args, r: RefreshArgs, limiter: Limiter, knobs: PoliteKnobs, scan_id: i64) -> Result<ScanStats> {
+
+    let conn = open_db(&args.db)?;
+    let targets = select_refresh_targets(&conn, r.hours, r.min_score, r.subreddit.as_deref(), r.limit)?;
+    drop(conn);
+    if targets.is_empty() {
+        eprintln!("[REFRESH] No posts match the filters");
+        return Ok(ScanStats::default());
+    }
+
+
+    let proxies = load_proxies(&args);
+
+
+    let dl = DeadLetter::new(&args.dead_letter);
+    let (tx, wt) = start_writer(&args, &dl);
+    let pool = start_image_pool(&args, &tx, &dl);
+    let failures = Arc::new(AtomicUsize::new(0));
+    take_rate_limited();
+
+
+    let mp = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+    let overall = overall_bar(&mp, targets.len(), "posts")?;
+    show_queue_depth(&overall, &tx);
+
+    let listing = Listing::new("refresh", "");
+    let mut js = JoinSet::new();
+    let per_worker = targets.len().div_ceil(args.workers);
+
+    for w in 0..args.workers {
+        let slice = targets.iter().skip(w * per_worker).take(per_worker).cloned().collect::<Vec<_>>();
+        if slice.is_empty() { continue; }
+
+        let wbar = worker_bar(&mp, w)?;
+
+        let txc           = tx.clone();
+        let dl_c          = dl.clone();
+        let failures_c    = failures.clone();
+        let images        = pool.queue();
+        let proxy         = worker_proxy(&proxies, w);
+        let limiter_c     = limiter.clone();
+        let knobs_c       = knobs;
+        let args_c        = args.clone();
+        let proxies_c     = proxies.clone();
+        let listing_c     = listing.clone();
+        let delay         = args.delay;
+        let expand        = args.expand_comments.then_some(args.max_comments_per_post);
+        let opts = BundleOpts {
+            scan_id,
+            max_comments: args.max_comments_per_post,
+        };
+        let overall_c = overall.clone();
+
+        js.spawn(async move {
+            let backend = match open_worker_backend(&args_c, w, &proxies_c).await {
+                Ok(b) => b,
+                Err(e) => { eprintln!("[worker {w}] start backend error: {e}"); wbar.finish_with_message("failed to start"); return; }
+            };
+
+            let mut last_ui = Instant::now();
+            let total = slice.len();
+
+            for (idx, (post_id, sub, url, created)) in slice.into_iter().enumerate() {
+                ui_set(&wbar, &mut last_ui, format!("r/{sub} • post {}/{} ({post_id})", idx + 1, total));
+                overall_c.inc(1);
+
+                match backend.post(&limiter_c, &post_id, knobs_c, expand).await {
+                    Ok(None) => {
+                        failures_c.fetch_add(1, Ordering::Relaxed);
+                        if !backend.alive().await { break; }
+                    }
+                    Ok(Some(v)) => {
+                        let bundle = build_bundle(v, (post_id, url, created), &sub, &listing_c, &opts);
+                        if !send_bundle(&txc, &dl_c, &images, bundle, proxy.as_deref()).await { break; }
+                        jitter_sleep(delay).await;
+                    }
+                    Err(e) => {
+                        failures_c.fetch_add(1, Ordering::Relaxed);
+                        if session_gone(&e) { eprintln!("[w{w}] session lost on post: {e}"); break; }
+                        eprintln!("[{sub}] post {post_id} parse error: {e}");
+                    }
+                }
+            }
+
+            backend.quit().await;
+            wbar.finish_and_clear();
+        });
+    }
+
+    drop(tx);
+
+
+    while js.join_next().await.is_some() {}
+
+    overall.finish_and_clear();
+    let _ = mp.clear();
 
-    Ok(total_saved)
+    finish_scan_stats(pool, wt, &dl, &failures).await
 }
 
 #[inline]
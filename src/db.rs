@@ -1,13 +1,84 @@
 use duckdb::{params, Connection};
+use crate::cli::Args;
+use crate::crawler::ScanStats;
+use crate::utils::now_secs;
+use crate::virality::Ranking;
+use crate::models::{CommentRow, CommentSnapshot, ImageFetch, MediaRow, PostRow, PostSnapshot, SubredditSnapshot};
 use anyhow::{Result, anyhow};
-use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
 
 pub fn open_db(path: &str) -> Result<Connection> {
     let conn = Connection::open(path)?;
+    conn.execute_batch("PRAGMA threads=4;")?;
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+struct Migration {
+    version: i64,
+    name: &'static str,
+    run: fn(&Connection) -> Result<()>,
+}
+
+// Applied in order, each at most once; `schema_version` records what a database has seen.
+// Append new steps here rather than editing old ones.
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "baseline", run: baseline },
+    Migration { version: 2, name: "primary keys", run: add_primary_keys },
+    Migration { version: 3, name: "export bookkeeping", run: add_exports },
+    Migration { version: 4, name: "scan lifecycle", run: add_scan_lifecycle },
+    Migration { version: 5, name: "age-normalized metrics", run: add_age_metrics },
+    Migration { version: 6, name: "trending", run: add_trending },
+    Migration { version: 7, name: "subreddit metrics", run: add_subreddit_metrics },
+    Migration { version: 8, name: "content version keys", run: key_content_versions },
+    Migration { version: 9, name: "exported scans", run: add_exported_scans },
+    Migration { version: 10, name: "export filters", run: add_export_filters },
+    Migration { version: 11, name: "state event times", run: date_state_events },
+];
+
+fn migrate(conn: &Connection) -> Result<()> {
     conn.execute_batch(r#"
-    PRAGMA threads=4;
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version BIGINT PRIMARY KEY,
+        name VARCHAR,
+        applied_at BIGINT
+    );
+    "#)?;
+    let current: i64 = conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |r| r.get(0))?;
+    let fresh = current == 0 && !table_exists(conn, "posts")?;
+    for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+        if !fresh { eprintln!("[DB] migrating to v{} ({})", m.version, m.name); }
+        (m.run)(conn).map_err(|e| anyhow!("migration v{} ({}) failed: {e}", m.version, m.name))?;
+        conn.execute(
+            "INSERT INTO schema_version(version, name, applied_at) VALUES (?, ?, ?)",
+            params![m.version, m.name, now_secs()]
+        )?;
+    }
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    let n: i64 = conn.query_row("SELECT count(*) FROM duckdb_tables() WHERE table_name = ?", params![table], |r| r.get(0))?;
+    Ok(n > 0)
+}
+
+pub fn in_tx(conn: &Connection, f: impl FnOnce(&Connection) -> Result<()>) -> Result<()> {
+    conn.execute_batch("BEGIN TRANSACTION;")?;
+    match f(conn) {
+        Ok(()) => { conn.execute_batch("COMMIT;")?; Ok(()) }
+        Err(e) => { let _ = conn.execute_batch("ROLLBACK;"); Err(e) }
+    }
+}
+
+// Everything up to the first versioned release. Each statement is idempotent, so this also brings
+// databases from before schema_version up to date, whatever release created them. Not one
+// transaction: DuckDB won't commit an ALTER and an UPDATE of the same table together.
+fn baseline(conn: &Connection) -> Result<()> {
+    conn.execute_batch(BASELINE)?;
+    in_tx(conn, migrate_images)
+}
 
-    -- Keep schema simple: no PRIMARY KEY / IDENTITY to avoid constraint errors
+const BASELINE: &str = r#"
     CREATE TABLE IF NOT EXISTS subreddits (
         id BIGINT,
         name VARCHAR
@@ -22,9 +93,35 @@ pub fn open_db(path: &str) -> Result<Connection> {
         score BIGINT,
         created_utc BIGINT,
         selftext VARCHAR,
-        num_comments BIGINT
+        num_comments BIGINT,
+        flair VARCHAR,
+        over_18 BOOLEAN,
+        spoiler BOOLEAN,
+        stickied BOOLEAN,
+        locked BOOLEAN,
+        domain VARCHAR,
+        crosspost_parent VARCHAR,
+        gilded BIGINT,
+        outbound_url VARCHAR,
+        upvote_ratio DOUBLE,
+        state VARCHAR
     );
 
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS flair VARCHAR;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS over_18 BOOLEAN;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS spoiler BOOLEAN;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS stickied BOOLEAN;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS locked BOOLEAN;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS domain VARCHAR;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS crosspost_parent VARCHAR;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS gilded BIGINT;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS outbound_url VARCHAR;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS upvote_ratio DOUBLE;
+    ALTER TABLE posts ADD COLUMN IF NOT EXISTS state VARCHAR;
+    UPDATE posts SET state = CASE
+        WHEN selftext = '[deleted]' THEN 'deleted' WHEN selftext = '[removed]' THEN 'removed' ELSE 'live' END
+    WHERE state IS NULL;
+
     CREATE TABLE IF NOT EXISTS comments (
         id VARCHAR,
         post_id VARCHAR,
@@ -32,30 +129,124 @@ pub fn open_db(path: &str) -> Result<Connection> {
         author VARCHAR,
         body VARCHAR,
         score BIGINT,
-        created_utc BIGINT
+        created_utc BIGINT,
+        tree_complete BOOLEAN,
+        depth BIGINT,
+        permalink VARCHAR,
+        edited BOOLEAN,
+        edited_utc BIGINT,
+        distinguished VARCHAR,
+        stickied BOOLEAN,
+        score_hidden BOOLEAN,
+        state VARCHAR
+    );
+
+    -- Whether the post's whole comment tree was captured (no unexpanded stubs, not capped)
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS tree_complete BOOLEAN;
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS depth BIGINT;
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS permalink VARCHAR;
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS edited BOOLEAN;
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS edited_utc BIGINT;
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS distinguished VARCHAR;
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS stickied BOOLEAN;
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS score_hidden BOOLEAN;
+    ALTER TABLE comments ADD COLUMN IF NOT EXISTS state VARCHAR;
+    UPDATE comments SET state = CASE
+        WHEN body = '[deleted]' THEN 'deleted' WHEN body = '[removed]' THEN 'removed' ELSE 'live' END
+    WHERE state IS NULL;
+
+    -- One row per live/deleted/removed transition; old_state is NULL when first seen already gone
+    CREATE TABLE IF NOT EXISTS state_events (
+        thing_id VARCHAR,
+        kind VARCHAR,
+        post_id VARCHAR,
+        scan_id BIGINT,
+        old_state VARCHAR,
+        new_state VARCHAR,
+        observed_at BIGINT
+    );
+
+    -- Post selftext / comment body as of each change; keyed (id, content_hash, scan_id) from v8
+    CREATE TABLE IF NOT EXISTS content_versions (
+        id VARCHAR,
+        kind VARCHAR,
+        content_hash VARCHAR,
+        body VARCHAR,
+        edited_utc BIGINT,
+        scan_id BIGINT,
+        seen_at BIGINT
     );
 
-    CREATE TABLE IF NOT EXISTS images (
+    -- Replaces the old flat `images` table (migrated below)
+    CREATE TABLE IF NOT EXISTS media (
         post_id VARCHAR,
+        position BIGINT,
+        kind VARCHAR,
         url VARCHAR,
-        data_base64 VARCHAR,
+        caption VARCHAR,
         mime VARCHAR,
-        size_bytes BIGINT
+        width BIGINT,
+        height BIGINT,
+        duration_secs DOUBLE,
+        fallback_url VARCHAR,
+        data_base64 VARCHAR,
+        size_bytes BIGINT,
+        content_hash VARCHAR,
+        phash BIGINT,
+        fetch_error VARCHAR
     );
 
+    ALTER TABLE media ADD COLUMN IF NOT EXISTS content_hash VARCHAR;
+    ALTER TABLE media ADD COLUMN IF NOT EXISTS phash BIGINT;
+    -- Why the image download failed (http status, size, mime, timeout…), NULL on success
+    ALTER TABLE media ADD COLUMN IF NOT EXISTS fetch_error VARCHAR;
+
     CREATE TABLE IF NOT EXISTS scans (
         id BIGINT,
         scanned_at BIGINT
     );
 
+    CREATE TABLE IF NOT EXISTS subreddit_snapshots (
+        subreddit_id BIGINT,
+        scan_id BIGINT,
+        subscribers BIGINT,
+        users_online BIGINT,
+        created_utc BIGINT,
+        description VARCHAR,
+        rules VARCHAR,
+        moderators VARCHAR
+    );
+
     CREATE TABLE IF NOT EXISTS post_snapshots (
         post_id VARCHAR,
         scan_id BIGINT,
         score BIGINT,
         num_comments BIGINT,
-        created_utc BIGINT
+        created_utc BIGINT,
+        listing_sort VARCHAR,
+        listing_time VARCHAR,
+        upvote_ratio DOUBLE,
+        flair VARCHAR,
+        over_18 BOOLEAN,
+        spoiler BOOLEAN,
+        stickied BOOLEAN,
+        locked BOOLEAN,
+        gilded BIGINT
     );
 
+    -- Databases created before listings were configurable
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS listing_sort VARCHAR;
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS listing_time VARCHAR;
+    -- Older scans always came from top/day
+    UPDATE post_snapshots SET listing_sort = 'top', listing_time = 'day' WHERE listing_sort IS NULL;
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS upvote_ratio DOUBLE;
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS flair VARCHAR;
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS over_18 BOOLEAN;
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS spoiler BOOLEAN;
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS stickied BOOLEAN;
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS locked BOOLEAN;
+    ALTER TABLE post_snapshots ADD COLUMN IF NOT EXISTS gilded BIGINT;
+
     CREATE TABLE IF NOT EXISTS comment_snapshots (
         comment_id VARCHAR,
         scan_id BIGINT,
@@ -76,9 +267,14 @@ pub fn open_db(path: &str) -> Result<Connection> {
         comments_delta BIGINT,
         score_vph DOUBLE,
         comments_vph DOUBLE,
-        virality_score DOUBLE
+        virality_score DOUBLE,
+        listing_sort VARCHAR,
+        listing_time VARCHAR
     );
 
+    ALTER TABLE post_metrics ADD COLUMN IF NOT EXISTS listing_sort VARCHAR;
+    ALTER TABLE post_metrics ADD COLUMN IF NOT EXISTS listing_time VARCHAR;
+
     CREATE TABLE IF NOT EXISTS comment_metrics (
         comment_id VARCHAR,
         post_id VARCHAR,
@@ -96,25 +292,378 @@ pub fn open_db(path: &str) -> Result<Connection> {
     CREATE INDEX IF NOT EXISTS idx_subs_id   ON subreddits(id);
     CREATE INDEX IF NOT EXISTS idx_posts_id  ON posts(id);
     CREATE INDEX IF NOT EXISTS idx_scans_id  ON scans(id);
+    CREATE INDEX IF NOT EXISTS idx_ss_sub_scan ON subreddit_snapshots(subreddit_id, scan_id);
     CREATE INDEX IF NOT EXISTS idx_ps_post_scan ON post_snapshots(post_id, scan_id);
     CREATE INDEX IF NOT EXISTS idx_cs_comment_scan ON comment_snapshots(comment_id, scan_id);
+    CREATE INDEX IF NOT EXISTS idx_comments_id ON comments(id);
+    CREATE INDEX IF NOT EXISTS idx_cv_id ON content_versions(id);
+    CREATE INDEX IF NOT EXISTS idx_media_post ON media(post_id);
+    CREATE INDEX IF NOT EXISTS idx_se_scan ON state_events(scan_id);
+"#;
+
+// Moves rows of the pre-`media` images table over once, in their insertion order.
+fn migrate_images(conn: &Connection) -> Result<()> {
+    if !table_exists(conn, "images")? { return Ok(()); }
+    conn.execute_batch(r#"
+    INSERT INTO media (post_id, position, kind, url, mime, data_base64, size_bytes)
+    SELECT post_id, row_number() OVER (PARTITION BY post_id ORDER BY rowid) - 1, 'image', url, mime, data_base64, size_bytes
+    FROM images;
+    DROP TABLE images;
     "#)?;
-    Ok(conn)
+    Ok(())
 }
 
+// (table, key columns)
+const KEYS: &[(&str, &str)] = &[
+    ("subreddits", "id"),
+    ("scans", "id"),
+    ("posts", "id"),
+    ("comments", "id"),
+    ("media", "post_id, position"),
+    ("subreddit_snapshots", "subreddit_id, scan_id"),
+    ("post_snapshots", "post_id, scan_id, listing_sort, listing_time"),
+    ("comment_snapshots", "comment_id, scan_id"),
+    ("post_metrics", "post_id, scan_id, listing_sort, listing_time"),
+    ("comment_metrics", "comment_id, scan_id"),
+];
+
+// Keys on the tables that were append-only with DELETE+INSERT upserts. Rows that piled up under
+// one key are collapsed to the most recently written one first.
+fn add_primary_keys(conn: &Connection) -> Result<()> {
+    in_tx(conn, |c| Ok(c.execute_batch(r#"
+    -- a name that got several ids keeps the lowest
+    UPDATE posts SET subreddit_id = k.keep
+    FROM subreddits s, (SELECT name, MIN(id) AS keep FROM subreddits GROUP BY name) k
+    WHERE posts.subreddit_id = s.id AND s.name = k.name AND s.id <> k.keep;
+    UPDATE subreddit_snapshots SET subreddit_id = k.keep
+    FROM subreddits s, (SELECT name, MIN(id) AS keep FROM subreddits GROUP BY name) k
+    WHERE subreddit_snapshots.subreddit_id = s.id AND s.name = k.name AND s.id <> k.keep;
+    DELETE FROM subreddits WHERE id NOT IN (SELECT MIN(id) FROM subreddits GROUP BY name);
 
+    UPDATE post_metrics SET listing_sort = 'top', listing_time = 'day' WHERE listing_sort IS NULL;
+    -- key columns can't be NULL; '' is "no time window"
+    UPDATE post_snapshots SET listing_time = '' WHERE listing_time IS NULL;
+    UPDATE post_metrics SET listing_time = '' WHERE listing_time IS NULL;
 
-fn now_secs() -> i64 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    -- plain indexes block ADD PRIMARY KEY, and the keys cover them
+    DROP INDEX IF EXISTS idx_subs_name;
+    DROP INDEX IF EXISTS idx_subs_id;
+    DROP INDEX IF EXISTS idx_posts_id;
+    DROP INDEX IF EXISTS idx_scans_id;
+    DROP INDEX IF EXISTS idx_ss_sub_scan;
+    DROP INDEX IF EXISTS idx_ps_post_scan;
+    DROP INDEX IF EXISTS idx_cs_comment_scan;
+    DROP INDEX IF EXISTS idx_comments_id;
+    DROP INDEX IF EXISTS idx_media_post;
+    "#)?))?;
+
+    for (table, key) in KEYS {
+        in_tx(conn, |c| dedup(c, table, key))?;
+        // DuckDB only accepts the key once the deletes above are committed
+        let has_key: i64 = conn.query_row(
+            "SELECT count(*) FROM duckdb_constraints() WHERE table_name = ? AND constraint_type = 'PRIMARY KEY'",
+            params![table], |r| r.get(0)
+        )?;
+        if has_key == 0 {
+            conn.execute_batch(&format!("ALTER TABLE {table} ADD PRIMARY KEY ({key});"))?;
+        }
+    }
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_subs_name ON subreddits(name);")?;
+    Ok(())
 }
 
-pub fn start_scan(conn: &Connection) -> Result<i64> {
+fn dedup(conn: &Connection, table: &str, key: &str) -> Result<()> {
+    let nulls = key.split(", ").map(|k| format!("{k} IS NULL")).collect::<Vec<_>>().join(" OR ");
+    conn.execute_batch(&format!("DELETE FROM {table} WHERE {nulls};"))?;
+    let dups: i64 = conn.query_row(
+        &format!("SELECT count(*) - (SELECT count(*) FROM (SELECT DISTINCT {key} FROM {table})) FROM {table}"),
+        [], |r| r.get(0)
+    )?;
+    if dups == 0 { return Ok(()); }
+    conn.execute_batch(&format!(r#"
+    CREATE TEMP TABLE _dedup AS
+    SELECT * EXCLUDE (_rn) FROM (
+        SELECT *, row_number() OVER (PARTITION BY {key} ORDER BY rowid DESC) AS _rn FROM {table}
+    ) WHERE _rn = 1;
+    DELETE FROM {table};
+    INSERT INTO {table} SELECT * FROM _dedup;
+    DROP TABLE _dedup;
+    "#))?;
+    eprintln!("[DB] {table}: removed {dups} duplicate rows");
+    Ok(())
+}
+
+
+
+// One row per `export` run.
+fn add_exports(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    CREATE TABLE IF NOT EXISTS exports (
+        id BIGINT PRIMARY KEY,
+        format VARCHAR,
+        out_dir VARCHAR,
+        from_scan_id BIGINT,
+        to_scan_id BIGINT,
+        scans BIGINT,
+        rows BIGINT,
+        exported_at BIGINT
+    );
+    "#)?;
+    Ok(())
+}
+
+// Scans run from `running` to `completed` or `aborted`; one still `running` when the next starts
+// belonged to a process that died. Ids come from a sequence that continues after the old
+// second-based ids, and scans from before this version count as completed.
+fn add_scan_lifecycle(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS finished_at BIGINT;
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS status VARCHAR;
+    -- the command line as JSON
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS args VARCHAR;
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS posts BIGINT;
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS comments BIGINT;
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS images BIGINT;
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS rate_limited BIGINT;
+    -- pages, posts and images that could not be fetched
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS failures BIGINT;
+    ALTER TABLE scans ADD COLUMN IF NOT EXISTS error VARCHAR;
+    UPDATE scans SET status = 'completed' WHERE status IS NULL;
+    "#)?;
+    let next: i64 = conn.query_row("SELECT COALESCE(MAX(id), 0) + 1 FROM scans", [], |r| r.get(0))?;
+    conn.execute_batch(&format!("CREATE SEQUENCE IF NOT EXISTS scan_ids START {next};"))?;
+    Ok(())
+}
+
+// Metrics written before this version took dt from created_utc and are mostly zero; `backfill`
+// recomputes them.
+fn add_age_metrics(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    ALTER TABLE post_metrics ADD COLUMN IF NOT EXISTS age_hours DOUBLE;
+    ALTER TABLE post_metrics ADD COLUMN IF NOT EXISTS score_per_hour DOUBLE;
+    ALTER TABLE post_metrics ADD COLUMN IF NOT EXISTS comments_per_hour DOUBLE;
+    ALTER TABLE comment_metrics ADD COLUMN IF NOT EXISTS age_hours DOUBLE;
+    ALTER TABLE comment_metrics ADD COLUMN IF NOT EXISTS score_per_hour DOUBLE;
+    "#)?;
+    let stale: i64 = conn.query_row("SELECT count(*) FROM post_metrics", [], |r| r.get(0))?;
+    if stale > 0 { eprintln!("[DB] {stale} post_metrics rows predate scan-time velocity; run `backfill` to recompute them"); }
+    Ok(())
+}
+
+// Posts of each scan ranked by virality z-score against their subreddit (see virality.rs)
+fn add_trending(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    CREATE TABLE IF NOT EXISTS trending (
+        scan_id BIGINT,
+        post_id VARCHAR,
+        subreddit_id BIGINT,
+        model VARCHAR,
+        virality_score DOUBLE,
+        baseline_mean DOUBLE,
+        baseline_sd DOUBLE,
+        z_score DOUBLE,
+        rank BIGINT,
+        subreddit_rank BIGINT,
+        PRIMARY KEY (scan_id, post_id)
+    );
+    "#)?;
+    Ok(())
+}
 
-    let id = now_secs();
-    conn.execute("INSERT INTO scans(id, scanned_at) VALUES (?, ?)", params![id, id])?;
+// Versions are keyed by (id, content_hash, scan_id): a body that goes A -> B -> A gets a row for
+// each change, including the revert. seen_at becomes the scan's start time rather than the
+// moment the row was written, which was off for anything written later by `replay`.
+fn key_content_versions(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    DROP INDEX IF EXISTS idx_cv_id;
+    UPDATE content_versions SET seen_at = s.scanned_at FROM scans s WHERE s.id = content_versions.scan_id;
+    "#)?;
+    in_tx(conn, |c| dedup(c, "content_versions", "id, content_hash, scan_id"))?;
+    conn.execute_batch("ALTER TABLE content_versions ADD PRIMARY KEY (id, content_hash, scan_id);")?;
+    Ok(())
+}
+
+// Which scans each export directory has, so an incremental export picks up exactly the completed
+// scans it lacks, including ones that `replay` completed after later scans were exported. Earlier
+// exports are assumed to hold every scan in their from..to range.
+fn add_exported_scans(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    CREATE TABLE IF NOT EXISTS exported_scans (
+        format VARCHAR,
+        out_dir VARCHAR,
+        scan_id BIGINT,
+        export_id BIGINT,
+        PRIMARY KEY (format, out_dir, scan_id)
+    );
+    INSERT INTO exported_scans
+    SELECT e.format, e.out_dir, s.id, MIN(e.id)
+    FROM exports e JOIN scans s ON s.id BETWEEN e.from_scan_id AND e.to_scan_id
+    GROUP BY e.format, e.out_dir, s.id
+    ON CONFLICT DO NOTHING;
+    "#)?;
+    Ok(())
+}
+
+// The --subreddit / --min-score an export ran with, '' for none.
+fn add_export_filters(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    ALTER TABLE exports ADD COLUMN IF NOT EXISTS filters VARCHAR;
+    UPDATE exports SET filters = '' WHERE filters IS NULL;
+    "#)?;
+    Ok(())
+}
+
+// observed_at becomes the scan's start time, as content_versions.seen_at did in v8.
+fn date_state_events(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    UPDATE state_events SET observed_at = s.scanned_at FROM scans s WHERE s.id = state_events.scan_id;
+    "#)?;
+    Ok(())
+}
+
+fn add_subreddit_metrics(conn: &Connection) -> Result<()> {
+    conn.execute_batch(r#"
+    CREATE TABLE IF NOT EXISTS subreddit_metrics (
+        subreddit_id BIGINT,
+        scan_id BIGINT,
+        posts_seen BIGINT,
+        -- posts no earlier completed scan had seen
+        new_posts BIGINT,
+        median_score DOUBLE,
+        p90_score DOUBLE,
+        total_comments BIGINT,
+        -- sum of the posts' comments_vph
+        comments_vph DOUBLE,
+        nsfw_share DOUBLE,
+        removed_share DOUBLE,
+        -- mean hours since creation of the posts found through a `top` listing
+        avg_top_age_hours DOUBLE,
+        PRIMARY KEY (subreddit_id, scan_id)
+    );
+    "#)?;
+    Ok(())
+}
+
+pub fn start_scan(conn: &Connection, args: &Args) -> Result<i64> {
+
+    let now = now_secs();
+    conn.execute(
+        "UPDATE scans SET status = 'aborted', error = 'interrupted' WHERE status = 'running'", []
+    )?;
+    let id: i64 = conn.query_row("SELECT nextval('scan_ids')", [], |r| r.get(0))?;
+    conn.execute(
+        "INSERT INTO scans(id, scanned_at, status, args) VALUES (?, ?, 'running', ?)",
+        params![id, now, serde_json::to_string(args)?]
+    )?;
     Ok(id)
 }
 
+// Posts and comments are counted from the snapshots the scan actually wrote, so an aborted scan
+// still reports what it saved.
+pub fn finish_scan(conn: &Connection, scan_id: i64, s: &ScanStats, error: Option<&str>) -> Result<()> {
+    conn.execute(r#"
+        UPDATE scans SET
+            finished_at = ?, status = ?, error = ?, images = ?, rate_limited = ?, failures = ?,
+            posts = (SELECT count(DISTINCT post_id) FROM post_snapshots WHERE scan_id = scans.id),
+            comments = (SELECT count(*) FROM comment_snapshots WHERE scan_id = scans.id)
+        WHERE id = ?
+    "#, params![
+        now_secs(), if error.is_some() { "aborted" } else { "completed" }, error,
+        s.images as i64, s.rate_limited as i64, s.failures as i64, scan_id
+    ])?;
+    Ok(())
+}
+
+// After `replay` has written an aborted scan's leftover messages: the scan counts as completed
+// from then on (its error is kept), with posts and comments recounted from the snapshots.
+pub fn complete_replayed_scan(conn: &Connection, scan_id: i64) -> Result<()> {
+    conn.execute(r#"
+        UPDATE scans SET
+            status = CASE WHEN status = 'aborted' THEN 'completed' ELSE status END,
+            posts = (SELECT count(DISTINCT post_id) FROM post_snapshots WHERE scan_id = scans.id),
+            comments = (SELECT count(*) FROM comment_snapshots WHERE scan_id = scans.id)
+        WHERE id = ?
+    "#, params![scan_id])?;
+    Ok(())
+}
+
+pub fn scan_status(conn: &Connection, scan_id: i64) -> Result<Option<String>> {
+    Ok(conn.query_row("SELECT MAX(status) FROM scans WHERE id = ?", params![scan_id], |r| r.get(0))?)
+}
+
+// (post id, subreddit name, url, created_utc)
+pub type RefreshTarget = (String, String, Option<String>, Option<i64>);
+
+pub fn select_refresh_targets(
+    conn: &Connection, hours: Option<i64>, min_score: Option<i64>, subreddit: Option<&str>, limit: usize
+) -> Result<Vec<RefreshTarget>> {
+    let since = hours.map(|h| now_secs() - h * 3600);
+    let mut stmt = conn.prepare(r#"
+        SELECT p.id, s.name, p.url, p.created_utc
+        FROM posts p
+        JOIN subreddits s ON s.id = p.subreddit_id
+        WHERE (CAST(? AS BIGINT) IS NULL OR p.created_utc >= ?)
+          AND (CAST(? AS BIGINT) IS NULL OR p.score >= ?)
+          AND (CAST(? AS VARCHAR) IS NULL OR lower(s.name) = lower(?))
+        ORDER BY p.created_utc DESC NULLS LAST
+        LIMIT ?
+    "#)?;
+    let rows = stmt.query_map(
+        params![since, since, min_score, min_score, subreddit, subreddit, limit as i64],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))
+    )?;
+    Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+// (id, scanned_at) of the scans after `after_scan_id` that are no longer running, oldest first
+pub fn scans_after(conn: &Connection, after_scan_id: i64) -> Result<Vec<(i64, i64)>> {
+    let mut stmt = conn.prepare("SELECT id, scanned_at FROM scans WHERE id > ? AND status <> 'running' ORDER BY id")?;
+    let rows = stmt.query_map(params![after_scan_id], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+// Completed scans as (id, scanned_at), oldest first. With `out_dir`, only those not exported
+// there in `format` yet.
+pub fn scans_to_export(conn: &Connection, format: &str, out_dir: Option<&str>) -> Result<Vec<(i64, i64)>> {
+    let mut stmt = conn.prepare(r#"
+        SELECT id, scanned_at FROM scans
+        WHERE status = 'completed'
+          AND id NOT IN (SELECT scan_id FROM exported_scans WHERE format = ? AND out_dir = ?)
+        ORDER BY id
+    "#)?;
+    let rows = stmt.query_map(params![format, out_dir], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+pub fn last_export_filters(conn: &Connection, format: &str, out_dir: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare("SELECT filters FROM exports WHERE format = ? AND out_dir = ? ORDER BY id DESC LIMIT 1")?;
+    let mut rows = stmt.query(params![format, out_dir])?;
+    Ok(match rows.next()? { Some(r) => r.get(0)?, None => None })
+}
+
+// `replace`: the export rewrote the directory, so it now holds these scans only.
+pub fn record_export(
+    conn: &Connection, format: &str, out_dir: &str, filters: &str, scans: &[(i64, i64)], rows: usize, replace: bool
+) -> Result<()> {
+    let (Some(first), Some(last)) = (scans.first(), scans.last()) else { return Ok(()) };
+    in_tx(conn, |c| {
+        if replace {
+            c.execute("DELETE FROM exported_scans WHERE format = ? AND out_dir = ?", params![format, out_dir])?;
+        }
+        let id: i64 = c.query_row("SELECT COALESCE(MAX(id)+1, 1) FROM exports", [], |r| r.get(0))?;
+        c.execute(
+            r#"INSERT INTO exports (id, format, out_dir, from_scan_id, to_scan_id, scans, rows, exported_at, filters)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+            params![id, format, out_dir, first.0, last.0, scans.len() as i64, rows as i64, now_secs(), filters]
+        )?;
+        let mut stmt = c.prepare("INSERT INTO exported_scans VALUES (?, ?, ?, ?) ON CONFLICT DO NOTHING")?;
+        for (scan_id, _) in scans {
+            stmt.execute(params![format, out_dir, scan_id, id])?;
+        }
+        Ok(())
+    })
+}
+
 pub fn upsert_subreddit(conn: &Connection, name: &str) -> Result<i64> {
 
     if let Ok(mut stmt) = conn.prepare("SELECT id FROM subreddits WHERE name = ? LIMIT 1") {
@@ -129,141 +678,487 @@ pub fn upsert_subreddit(conn: &Connection, name: &str) -> Result<i64> {
     let mut rid_rows = rid_stmt.query([])?;
     let new_id: i64 = rid_rows.next()?.ok_or_else(|| anyhow!("failed to alloc subreddit id"))?.get(0)?;
 
-    conn.execute("INSERT INTO subreddits(id, name) VALUES (?, ?)", params![new_id, name])?;
+    conn.execute("INSERT INTO subreddits(id, name) VALUES (?, ?) ON CONFLICT DO NOTHING", params![new_id, name])?;
     Ok(new_id)
 }
 
 
 
-pub fn upsert_post(
-    conn: &Connection,
-    id: &str, subreddit_id: i64, url: &str, title: Option<&str>, author: Option<&str>,
-    score: Option<i64>, created_utc: Option<i64>, selftext: Option<&str>, num_comments: Option<i64>
+// (state, author, body) of the stored row
+type PriorContent = (Option<String>, Option<String>, Option<String>);
+
+fn prior_content(conn: &Connection, sql: &str, id: &str) -> Result<Option<PriorContent>> {
+    let mut stmt = conn.prepare_cached(sql)?;
+    let mut rows = stmt.query(params![id])?;
+    match rows.next()? {
+        Some(r) => Ok(Some((r.get(0)?, r.get(1)?, r.get(2)?))),
+        None => Ok(None),
+    }
+}
+
+fn record_state_change(
+    conn: &Connection, kind: &str, id: &str, post_id: &str, scan_id: i64, old: Option<&str>, new: &str
 ) -> Result<()> {
-    conn.execute("DELETE FROM posts WHERE id = ?", params![id])?;
-    conn.execute(
-        r#"INSERT INTO posts
-           (id, subreddit_id, url, title, author, score, created_utc, selftext, num_comments)
-           VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
-        params![id, subreddit_id, url, title, author, score, created_utc, selftext, num_comments]
-    )?;
+    if old == Some(new) || (old.is_none() && new == "live") { return Ok(()); }
+    conn.prepare_cached(
+        r#"INSERT INTO state_events
+           (thing_id, kind, post_id, scan_id, old_state, new_state, observed_at)
+           VALUES (?, ?, ?, ?, ?, ?, COALESCE((SELECT scanned_at FROM scans WHERE id = ?), ?))"#
+    )?.execute(params![id, kind, post_id, scan_id, old, new, scan_id, now_secs()])?;
     Ok(())
 }
 
-pub fn upsert_comment(
-    conn: &Connection,
-    id: &str, post_id: &str, parent_fullname: Option<&str>, author: Option<&str>, body: Option<&str>,
-    score: Option<i64>, created_utc: Option<i64>
+// Once deleted/removed, the page only shows placeholders, so keep the last known author and body.
+fn keep_known(new: Option<&str>, prior: Option<String>, gone: bool) -> Option<String> {
+    if gone && prior.is_some() { prior } else { new.map(|s| s.to_string()) }
+}
+
+pub fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Adds a version only when the text differs from the latest one stored for this id.
+fn record_version(
+    conn: &Connection, kind: &str, id: &str, body: Option<&str>, edited_utc: Option<i64>, scan_id: i64
 ) -> Result<()> {
-    conn.execute("DELETE FROM comments WHERE id = ?", params![id])?;
-    conn.execute(
-        r#"INSERT INTO comments
-           (id, post_id, parent_fullname, author, body, score, created_utc)
-           VALUES (?, ?, ?, ?, ?, ?, ?)"#,
-        params![id, post_id, parent_fullname, author, body, score, created_utc]
+    let Some(body) = body else { return Ok(()) };
+    let hash = content_hash(body.as_bytes());
+    // compare with the version as of this scan, which is not the newest one when replaying
+    let mut stmt = conn.prepare_cached(
+        "SELECT content_hash FROM content_versions WHERE id = ? AND scan_id <= ? ORDER BY scan_id DESC LIMIT 1"
     )?;
+    let mut rows = stmt.query(params![id, scan_id])?;
+    if let Some(r) = rows.next()? {
+        let last: String = r.get(0)?;
+        if last == hash { return Ok(()); }
+    }
+    conn.prepare_cached(
+        r#"INSERT INTO content_versions
+           (id, kind, content_hash, body, edited_utc, scan_id, seen_at)
+           VALUES (?, ?, ?, ?, ?, ?, COALESCE((SELECT scanned_at FROM scans WHERE id = ?), ?))
+           ON CONFLICT DO NOTHING"#
+    )?.execute(params![id, kind, hash, body, edited_utc, scan_id, scan_id, now_secs()])?;
     Ok(())
 }
 
-pub fn ensure_image(
-    conn: &Connection, post_id: &str, url: &str, b64: Option<&str>, mime: Option<&str>, size: Option<i64>
-) -> Result<()> {
-    conn.execute("DELETE FROM images WHERE post_id = ? AND url = ?", params![post_id, url])?;
-    conn.execute(
-        r#"INSERT INTO images
-           (post_id, url, data_base64, mime, size_bytes)
-           VALUES (?, ?, ?, ?, ?)"#,
-        params![post_id, url, b64, mime, size]
-    )?;
+// (seen_at, scan_id, edited_utc, content_hash, body)
+pub type ContentVersion = (i64, i64, Option<i64>, String, Option<String>);
+
+pub fn content_history(conn: &Connection, id: &str) -> Result<Vec<ContentVersion>> {
+    let mut stmt = conn.prepare(r#"
+        SELECT seen_at, scan_id, edited_utc, content_hash, body
+        FROM content_versions WHERE id = ?
+        ORDER BY seen_at, scan_id
+    "#)?;
+    let rows = stmt.query_map(params![id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)))?;
+    Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+// (observed_at, scan_id, old_state, new_state)
+pub type StateEvent = (i64, i64, Option<String>, String);
+
+pub fn state_history(conn: &Connection, id: &str) -> Result<Vec<StateEvent>> {
+    let mut stmt = conn.prepare(r#"
+        SELECT observed_at, scan_id, old_state, new_state
+        FROM state_events WHERE thing_id = ?
+        ORDER BY observed_at, scan_id
+    "#)?;
+    let rows = stmt.query_map(params![id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?;
+    Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+// "post" / "comment" for a known id, None if we never stored it
+pub fn thing_kind(conn: &Connection, id: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare(r#"
+        SELECT 'post' FROM posts WHERE id = ?
+        UNION ALL SELECT 'comment' FROM comments WHERE id = ?
+        LIMIT 1
+    "#)?;
+    let mut rows = stmt.query(params![id, id])?;
+    Ok(match rows.next()? {
+        Some(r) => Some(r.get(0)?),
+        None => None,
+    })
+}
+
+pub fn upsert_post(conn: &Connection, subreddit_id: i64, p: &PostRow, scan_id: i64) -> Result<()> {
+    let prior = prior_content(conn, "SELECT state, author, selftext FROM posts WHERE id = ? LIMIT 1", &p.id)?;
+    let (old_state, old_author, old_body) = prior.clone().unwrap_or_default();
+    let gone = p.state != "live";
+    let author = keep_known(p.author.as_deref(), old_author, gone);
+    let selftext = keep_known(p.selftext.as_deref(), old_body, gone);
+    record_state_change(conn, "post", &p.id, &p.id, scan_id, old_state.as_deref().or(prior.map(|_| "live")), p.state)?;
+    if !gone { record_version(conn, "post", &p.id, p.selftext.as_deref(), None, scan_id)?; }
+
+    conn.prepare_cached(
+        r#"INSERT INTO posts
+           (id, subreddit_id, url, title, author, score, created_utc, selftext, num_comments,
+            flair, over_18, spoiler, stickied, locked, domain, crosspost_parent, gilded, outbound_url, upvote_ratio,
+            state)
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+           ON CONFLICT (id) DO UPDATE SET
+               subreddit_id = excluded.subreddit_id, url = excluded.url, title = excluded.title,
+               author = excluded.author, score = excluded.score, created_utc = excluded.created_utc,
+               selftext = excluded.selftext, num_comments = excluded.num_comments, flair = excluded.flair,
+               over_18 = excluded.over_18, spoiler = excluded.spoiler, stickied = excluded.stickied,
+               locked = excluded.locked, domain = excluded.domain, crosspost_parent = excluded.crosspost_parent,
+               gilded = excluded.gilded, outbound_url = excluded.outbound_url, upvote_ratio = excluded.upvote_ratio,
+               state = excluded.state"#
+    )?.execute(params![
+        p.id, subreddit_id, p.url, p.title, author, p.score, p.created_utc, selftext, p.num_comments,
+        p.flair, p.over_18, p.spoiler, p.stickied, p.locked, p.domain, p.crosspost_parent, p.gilded,
+        p.outbound_url, p.upvote_ratio, p.state
+    ])?;
     Ok(())
 }
 
-pub fn snapshot_post(
-    conn: &Connection, post_id: &str, scan_id: i64, score: Option<i64>, num_comments: Option<i64>, created_utc: Option<i64>
-) -> Result<()> {
-    conn.execute("DELETE FROM post_snapshots WHERE post_id = ? AND scan_id = ?", params![post_id, scan_id])?;
+pub fn upsert_comment(conn: &Connection, c: &CommentRow, scan_id: i64) -> Result<()> {
+    let prior = prior_content(conn, "SELECT state, author, body FROM comments WHERE id = ? LIMIT 1", &c.id)?;
+    let (old_state, old_author, old_body) = prior.clone().unwrap_or_default();
+    let gone = c.state != "live";
+    let author = keep_known(c.author.as_deref(), old_author, gone);
+    let body = keep_known(c.body.as_deref(), old_body, gone);
+    record_state_change(conn, "comment", &c.id, &c.post_id, scan_id, old_state.as_deref().or(prior.map(|_| "live")), c.state)?;
+    if !gone { record_version(conn, "comment", &c.id, c.body.as_deref(), c.edited_utc, scan_id)?; }
+
+    conn.prepare_cached(
+        r#"INSERT INTO comments
+           (id, post_id, parent_fullname, author, body, score, created_utc,
+            depth, permalink, edited, edited_utc, distinguished, stickied, score_hidden, state)
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+           ON CONFLICT (id) DO UPDATE SET
+               post_id = excluded.post_id, parent_fullname = excluded.parent_fullname, author = excluded.author,
+               body = excluded.body, score = excluded.score, created_utc = excluded.created_utc,
+               depth = excluded.depth, permalink = excluded.permalink, edited = excluded.edited,
+               edited_utc = excluded.edited_utc, distinguished = excluded.distinguished,
+               stickied = excluded.stickied, score_hidden = excluded.score_hidden, state = excluded.state"#
+    )?.execute(params![
+        c.id, c.post_id, c.parent_fullname, author, body, c.score, c.created_utc,
+        c.depth, c.permalink, c.edited, c.edited_utc, c.distinguished, c.stickied, c.score_hidden, c.state
+    ])?;
+    Ok(())
+}
+
+pub fn mark_comment_tree(conn: &Connection, post_id: &str, complete: bool) -> Result<()> {
+    conn.execute("UPDATE comments SET tree_complete = ? WHERE post_id = ?", params![complete, post_id])?;
+    Ok(())
+}
+
+pub fn replace_media(conn: &Connection, post_id: &str, media: &[MediaRow]) -> Result<()> {
+    conn.execute("DELETE FROM media WHERE post_id = ? AND position >= ?", params![post_id, media.len() as i64])?;
+    for m in media {
+        // a new URL at the same position invalidates what was downloaded for the old one
+        conn.execute(
+            r#"INSERT INTO media
+               (post_id, position, kind, url, caption, mime, width, height, duration_secs, fallback_url)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+               ON CONFLICT (post_id, position) DO UPDATE SET
+                   kind = excluded.kind, url = excluded.url, caption = excluded.caption, mime = excluded.mime,
+                   width = excluded.width, height = excluded.height, duration_secs = excluded.duration_secs,
+                   fallback_url = excluded.fallback_url,
+                   data_base64 = CASE WHEN media.url = excluded.url THEN media.data_base64 END,
+                   size_bytes = CASE WHEN media.url = excluded.url THEN media.size_bytes END,
+                   content_hash = CASE WHEN media.url = excluded.url THEN media.content_hash END,
+                   phash = CASE WHEN media.url = excluded.url THEN media.phash END,
+                   fetch_error = CASE WHEN media.url = excluded.url THEN media.fetch_error END"#,
+            params![
+                post_id, m.position, m.kind, m.url, m.caption, m.mime, m.width, m.height, m.duration_secs,
+                m.fallback_url
+            ]
+        )?;
+    }
+    Ok(())
+}
+
+pub fn record_image_fetch(conn: &Connection, f: &ImageFetch) -> Result<()> {
     conn.execute(
-        r#"INSERT INTO post_snapshots
-           (post_id, scan_id, score, num_comments, created_utc)
-           VALUES (?, ?, ?, ?, ?)"#,
-        params![post_id, scan_id, score, num_comments, created_utc]
+        r#"UPDATE media SET
+               mime = COALESCE(?, mime), size_bytes = ?, width = COALESCE(?, width), height = COALESCE(?, height),
+               data_base64 = ?, content_hash = ?, phash = ?, fetch_error = ?
+           WHERE post_id = ? AND position = ? AND url = ?"#,
+        params![
+            f.mime, f.size_bytes, f.width, f.height, f.data_base64, f.content_hash, f.phash, f.error,
+            f.post_id, f.position, f.url
+        ]
     )?;
     Ok(())
 }
 
-pub fn snapshot_comment(
-    conn: &Connection, comment_id: &str, scan_id: i64, score: Option<i64>, created_utc: Option<i64>
-) -> Result<()> {
-    conn.execute("DELETE FROM comment_snapshots WHERE comment_id = ? AND scan_id = ?", params![comment_id, scan_id])?;
-    conn.execute(
-        r#"INSERT INTO comment_snapshots
-           (comment_id, scan_id, score, created_utc)
-           VALUES (?, ?, ?, ?)"#,
-        params![comment_id, scan_id, score, created_utc]
+pub fn referenced_blobs(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT DISTINCT content_hash FROM media WHERE content_hash IS NOT NULL")?;
+    let rows = stmt.query_map([], |r| r.get(0))?;
+    Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+// (distance, earlier post, its subreddit, later post, its subreddit, earlier image url, later image url)
+pub type RepostPair = (i64, String, String, String, String, String, String);
+
+// Image pairs on different posts whose dHashes are within `max_distance` (0..=63) bits, closest
+// first. The first post of a pair is the older one. Candidates come from splitting each hash into
+// max_distance + 1 bands: two hashes that close agree exactly on at least one band, so only
+// images sharing a band value are compared.
+pub fn find_reposts(
+    conn: &Connection, max_distance: i64, subreddit: Option<&str>, limit: usize
+) -> Result<Vec<RepostPair>> {
+    let bands = (max_distance.clamp(0, 63) + 1) as u32;
+    let keys = (0..bands).map(|i| {
+        let (width, start) = (64 / bands + u32::from(i < 64 % bands), i * (64 / bands) + i.min(64 % bands));
+        if width == 64 { "phash".to_string() } else { format!("(phash >> {start}) & {}", (1u64 << width) - 1) }
+    }).collect::<Vec<_>>().join(", ");
+    let mut stmt = conn.prepare(&format!(r#"
+        WITH m AS (
+            SELECT m.post_id, m.url, m.phash, COALESCE(p.created_utc, 0) AS created_utc, s.name AS sub
+            FROM media m
+            JOIN posts p ON p.id = m.post_id
+            JOIN subreddits s ON s.id = p.subreddit_id
+            WHERE m.phash IS NOT NULL
+        ),
+        banded AS (
+            SELECT *, unnest(range({bands})) AS band, unnest([{keys}]) AS band_key FROM m
+        ),
+        pairs AS (
+            SELECT DISTINCT ON (a.post_id, b.post_id)
+                   bit_count(xor(a.phash, b.phash)) AS d,
+                   a.post_id AS a_post, a.sub AS a_sub, b.post_id AS b_post, b.sub AS b_sub, a.url AS a_url, b.url AS b_url
+            FROM banded a JOIN banded b
+              ON a.band = b.band AND a.band_key = b.band_key
+             AND (a.created_utc, a.post_id) < (b.created_utc, b.post_id)
+            WHERE bit_count(xor(a.phash, b.phash)) <= ?
+              AND (CAST(? AS VARCHAR) IS NULL OR lower(a.sub) = lower(?) OR lower(b.sub) = lower(?))
+            ORDER BY a.post_id, b.post_id, d
+        )
+        SELECT * FROM pairs ORDER BY d, a_post, b_post LIMIT ?
+    "#))?;
+    let rows = stmt.query_map(
+        params![max_distance, subreddit, subreddit, subreddit, limit as i64],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?))
     )?;
+    Ok(rows.collect::<std::result::Result<Vec<RepostPair>, _>>()?)
+}
+
+// The Appender can't upsert, so snapshot rows are appended to per-connection TEMP copies of the
+// snapshot tables and merged into the keyed tables once per batch, latest row per key winning.
+const STAGES: &[(&str, &str, &str)] = &[
+    (
+        "post_snapshots",
+        "post_id, scan_id, score, num_comments, created_utc, listing_sort, listing_time, \
+         upvote_ratio, flair, over_18, spoiler, stickied, locked, gilded",
+        "post_id, scan_id, listing_sort, listing_time",
+    ),
+    ("comment_snapshots", "comment_id, scan_id, score, created_utc", "comment_id, scan_id"),
+    (
+        "subreddit_snapshots",
+        "subreddit_id, scan_id, subscribers, users_online, created_utc, description, rules, moderators",
+        "subreddit_id, scan_id",
+    ),
+];
+
+pub fn create_snapshot_stages(conn: &Connection) -> Result<()> {
+    for (table, cols, _) in STAGES {
+        conn.execute_batch(&format!("CREATE TEMP TABLE IF NOT EXISTS stage_{table} AS SELECT {cols} FROM {table} LIMIT 0;"))?;
+    }
     Ok(())
 }
 
+#[derive(Debug, Default)]
+pub struct SnapshotBatch {
+    pub posts: Vec<PostSnapshot>,
+    pub comments: Vec<CommentSnapshot>,
+    pub subreddits: Vec<(i64, SubredditSnapshot)>,
+}
+
+// Meant to run inside the writer's transaction, after the rows the snapshots refer to.
+pub fn write_snapshots(conn: &Connection, b: &SnapshotBatch) -> Result<()> {
+    let mut app = conn.appender("stage_post_snapshots")?;
+    for s in &b.posts {
+        app.append_row(params![
+            s.post_id, s.scan_id, s.score, s.num_comments, s.created_utc, s.listing.sort, s.listing.key_time(),
+            s.upvote_ratio, s.flair, s.over_18, s.spoiler, s.stickied, s.locked, s.gilded
+        ])?;
+    }
+    app.flush()?;
+    drop(app);
+
+    let mut app = conn.appender("stage_comment_snapshots")?;
+    for s in &b.comments {
+        app.append_row(params![s.comment_id, s.scan_id, s.score, s.created_utc])?;
+    }
+    app.flush()?;
+    drop(app);
+
+    let mut app = conn.appender("stage_subreddit_snapshots")?;
+    for (sub_id, s) in &b.subreddits {
+        app.append_row(params![
+            sub_id, s.scan_id, s.subscribers, s.users_online, s.created_utc, s.description, s.rules, s.moderators
+        ])?;
+    }
+    app.flush()?;
+    drop(app);
+
+    for (table, cols, key) in STAGES {
+        conn.execute_batch(&format!(r#"
+        INSERT OR REPLACE INTO {table} ({cols})
+        SELECT {cols} FROM stage_{table}
+        QUALIFY row_number() OVER (PARTITION BY {key} ORDER BY rowid DESC) = 1;
+        DELETE FROM stage_{table};
+        "#))?;
+    }
+    Ok(())
+}
 
 
+
+pub fn compute_metrics(conn: &Connection, scan_id: i64, ranking: &Ranking) -> Result<()> {
+    let status = scan_status(conn, scan_id)?;
+    if status.as_deref() != Some("completed") {
+        eprintln!("[METRICS] Skipping scan {scan_id} ({})", status.as_deref().unwrap_or("unknown"));
+        return Ok(());
+    }
+    eprintln!("[METRICS] Computing post metrics...");
+    compute_post_metrics(conn, scan_id)?;
+    eprintln!("[METRICS] Ranking trending posts...");
+    ranking.compute(conn, scan_id)?;
+    eprintln!("[METRICS] Computing subreddit metrics...");
+    compute_subreddit_metrics(conn, scan_id)?;
+    eprintln!("[METRICS] Computing comment metrics...");
+    compute_comment_metrics(conn, scan_id)?;
+    Ok(())
+}
+
+// Velocity is measured between the two scans' start times. Age-normalized rates divide the
+// current totals by the hours since the post or comment was created; both are NULL while the
+// elapsed time is zero.
 pub fn compute_post_metrics(conn: &Connection, scan_id: i64) -> Result<()> {
     conn.execute_batch(&format!(r#"
-    INSERT INTO post_metrics
+    INSERT OR REPLACE INTO post_metrics
     SELECT
-        s.post_id,
-        s.scan_id,
-        s.score,
-        s.num_comments,
-        p.scan_id  AS prev_scan_id,
-        p.score    AS prev_score,
-        p.num_comments AS prev_num_comments,
-        (s.created_utc - p.created_utc) AS dt_seconds,
-        (s.score - p.score)             AS score_delta,
-        (s.num_comments - p.num_comments) AS comments_delta,
-        CASE WHEN (s.created_utc - p.created_utc) > 0
-             THEN (s.score - p.score) * 3600.0 / (s.created_utc - p.created_utc) END AS score_vph,
-        CASE WHEN (s.created_utc - p.created_utc) > 0
-             THEN (s.num_comments - p.num_comments) * 3600.0 / (s.created_utc - p.created_utc) END AS comments_vph,
-        COALESCE(
-            (CASE WHEN (s.created_utc - p.created_utc) > 0
-                  THEN (s.score - p.score) * 3600.0 / (s.created_utc - p.created_utc) ELSE 0 END) * 0.6 +
-            (CASE WHEN (s.created_utc - p.created_utc) > 0
-                  THEN (s.num_comments - p.num_comments) * 3600.0 / (s.created_utc - p.created_utc) ELSE 0 END) * 0.4,
-            0
-        ) AS virality_score
-    FROM post_snapshots s
-    LEFT JOIN LATERAL (
-        SELECT * FROM post_snapshots ps
-        WHERE ps.post_id = s.post_id AND ps.scan_id < s.scan_id
-        ORDER BY ps.scan_id DESC LIMIT 1
-    ) p ON true
-    WHERE s.scan_id = {scan};
+        post_id, scan_id, score, num_comments, prev_scan_id, prev_score, prev_num_comments,
+        dt_seconds, score_delta, comments_delta, score_vph, comments_vph,
+        -- filled in by the virality model
+        NULL AS virality_score,
+        listing_sort, listing_time,
+        age_hours,
+        CASE WHEN age_hours > 0 THEN score / age_hours END AS score_per_hour,
+        CASE WHEN age_hours > 0 THEN num_comments / age_hours END AS comments_per_hour
+    FROM (
+        SELECT *,
+            CASE WHEN dt_seconds > 0 THEN score_delta * 3600.0 / dt_seconds END AS score_vph,
+            CASE WHEN dt_seconds > 0 THEN comments_delta * 3600.0 / dt_seconds END AS comments_vph
+        FROM (
+            SELECT
+                s.post_id,
+                s.scan_id,
+                s.score,
+                s.num_comments,
+                p.scan_id  AS prev_scan_id,
+                p.score    AS prev_score,
+                p.num_comments AS prev_num_comments,
+                (cur.scanned_at - p.scanned_at)   AS dt_seconds,
+                (s.score - p.score)               AS score_delta,
+                (s.num_comments - p.num_comments) AS comments_delta,
+                s.listing_sort,
+                s.listing_time,
+                (cur.scanned_at - s.created_utc) / 3600.0 AS age_hours
+            FROM post_snapshots s
+            JOIN scans cur ON cur.id = s.scan_id
+            LEFT JOIN LATERAL (
+                SELECT ps.*, sc.scanned_at FROM post_snapshots ps
+                JOIN scans sc ON sc.id = ps.scan_id AND sc.status = 'completed'
+                WHERE ps.post_id = s.post_id AND ps.scan_id < s.scan_id
+                  -- a refresh compares with the latest earlier snapshot from any listing
+                  AND (s.listing_sort = 'refresh' OR (
+                      ps.listing_sort IS NOT DISTINCT FROM s.listing_sort
+                      AND ps.listing_time IS NOT DISTINCT FROM s.listing_time))
+                -- a scan can hold the post under several listings; pick one the same way every run
+                ORDER BY ps.scan_id DESC, ps.listing_sort, ps.listing_time LIMIT 1
+            ) p ON true
+            WHERE s.scan_id = {scan}
+        )
+    );
+    "#, scan = scan_id))?;
+    Ok(())
+}
+
+// One row per subreddit from the posts its listings returned in the scan; `refresh` snapshots
+// don't count. A post seen in several listings counts once.
+pub fn compute_subreddit_metrics(conn: &Connection, scan_id: i64) -> Result<()> {
+    conn.execute_batch(&format!(r#"
+    INSERT OR REPLACE INTO subreddit_metrics
+    WITH seen AS (
+        SELECT s.post_id, p.subreddit_id,
+               MAX(s.score) AS score, MAX(s.num_comments) AS num_comments, BOOL_OR(s.over_18) AS over_18,
+               MAX(s.created_utc) AS created_utc, BOOL_OR(s.listing_sort = 'top') AS in_top
+        FROM post_snapshots s
+        JOIN posts p ON p.id = s.post_id
+        WHERE s.scan_id = {scan} AND s.listing_sort <> 'refresh'
+        GROUP BY s.post_id, p.subreddit_id
+    ),
+    -- state as of this scan: the last transition recorded up to it, live if there is none
+    state_at AS (
+        SELECT thing_id AS post_id, new_state AS state FROM state_events
+        WHERE kind = 'post' AND scan_id <= {scan} AND thing_id IN (SELECT post_id FROM seen)
+        QUALIFY row_number() OVER (PARTITION BY thing_id ORDER BY scan_id DESC, observed_at DESC) = 1
+    ),
+    earlier AS (
+        SELECT DISTINCT ps.post_id FROM post_snapshots ps
+        JOIN scans sc ON sc.id = ps.scan_id AND sc.status = 'completed'
+        WHERE ps.scan_id < {scan} AND ps.post_id IN (SELECT post_id FROM seen)
+    ),
+    vel AS (
+        SELECT post_id, MAX(comments_vph) AS comments_vph FROM post_metrics WHERE scan_id = {scan} GROUP BY post_id
+    )
+    SELECT
+        seen.subreddit_id,
+        {scan} AS scan_id,
+        count(*) AS posts_seen,
+        count(*) FILTER (WHERE earlier.post_id IS NULL) AS new_posts,
+        quantile_cont(seen.score, 0.5) AS median_score,
+        quantile_cont(seen.score, 0.9) AS p90_score,
+        sum(seen.num_comments) AS total_comments,
+        sum(vel.comments_vph) AS comments_vph,
+        avg(CASE WHEN seen.over_18 THEN 1.0 ELSE 0.0 END) AS nsfw_share,
+        avg(CASE WHEN state_at.state = 'removed' THEN 1.0 ELSE 0.0 END) AS removed_share,
+        avg((cur.scanned_at - seen.created_utc) / 3600.0) FILTER (WHERE seen.in_top) AS avg_top_age_hours
+    FROM seen
+    JOIN scans cur ON cur.id = {scan}
+    LEFT JOIN earlier ON earlier.post_id = seen.post_id
+    LEFT JOIN vel ON vel.post_id = seen.post_id
+    LEFT JOIN state_at ON state_at.post_id = seen.post_id
+    GROUP BY seen.subreddit_id;
     "#, scan = scan_id))?;
     Ok(())
 }
 
 pub fn compute_comment_metrics(conn: &Connection, scan_id: i64) -> Result<()> {
     conn.execute_batch(&format!(r#"
-    INSERT INTO comment_metrics
+    INSERT OR REPLACE INTO comment_metrics
     SELECT
-        s.comment_id,
-        c.post_id,
-        s.scan_id,
-        s.score,
-        p.scan_id  AS prev_scan_id,
-        p.score    AS prev_score,
-        (s.created_utc - p.created_utc) AS dt_seconds,
-        (s.score - p.score)             AS score_delta,
-        CASE WHEN (s.created_utc - p.created_utc) > 0
-             THEN (s.score - p.score) * 3600.0 / (s.created_utc - p.created_utc) END AS score_vph
-    FROM comment_snapshots s
-    JOIN comments c ON c.id = s.comment_id
-    LEFT JOIN LATERAL (
-        SELECT * FROM comment_snapshots cs
-        WHERE cs.comment_id = s.comment_id AND cs.scan_id < s.scan_id
-        ORDER BY cs.scan_id DESC LIMIT 1
-    ) p ON true
-    WHERE s.scan_id = {scan};
+        comment_id, post_id, scan_id, score, prev_scan_id, prev_score, dt_seconds, score_delta,
+        CASE WHEN dt_seconds > 0 THEN score_delta * 3600.0 / dt_seconds END AS score_vph,
+        age_hours,
+        CASE WHEN age_hours > 0 THEN score / age_hours END AS score_per_hour
+    FROM (
+        SELECT
+            s.comment_id,
+            c.post_id,
+            s.scan_id,
+            s.score,
+            p.scan_id  AS prev_scan_id,
+            p.score    AS prev_score,
+            (cur.scanned_at - p.scanned_at) AS dt_seconds,
+            (s.score - p.score)             AS score_delta,
+            (cur.scanned_at - s.created_utc) / 3600.0 AS age_hours
+        FROM comment_snapshots s
+        JOIN comments c ON c.id = s.comment_id
+        JOIN scans cur ON cur.id = s.scan_id
+        LEFT JOIN LATERAL (
+            SELECT cs.*, sc.scanned_at FROM comment_snapshots cs
+            JOIN scans sc ON sc.id = cs.scan_id AND sc.status = 'completed'
+            WHERE cs.comment_id = s.comment_id AND cs.scan_id < s.scan_id
+            ORDER BY cs.scan_id DESC LIMIT 1
+        ) p ON true
+        WHERE s.scan_id = {scan}
+    );
     "#, scan = scan_id))?;
     Ok(())
 }
@@ -0,0 +1,372 @@
+use crate::cli::{Args, ExportArgs};
+use crate::db::{last_export_filters, open_db, record_export, scans_to_export};
+
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use duckdb::types::ValueRef;
+use duckdb::{params, Connection};
+use serde_json::{json, Map, Value as JsonValue};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// (table, query) for parquet. Each query reads the scans being exported from `export_scans` and the
+// filtered `export_subs` / `export_posts` views, and yields `subreddit` and `scan_date` for
+// partitioning. Posts and comments are written once per scan that saw them, as they stand at
+// export time.
+const TABLES: &[(&str, &str)] = &[
+    ("posts", r#"
+        SELECT s.name AS subreddit, e.scan_date, ps.scan_id, p.*
+        FROM (SELECT DISTINCT post_id, scan_id FROM post_snapshots) ps
+        JOIN export_scans e ON e.id = ps.scan_id
+        JOIN export_posts p ON p.id = ps.post_id
+        JOIN export_subs s ON s.id = p.subreddit_id
+    "#),
+    ("comments", r#"
+        SELECT s.name AS subreddit, e.scan_date, cs.scan_id, c.*
+        FROM comment_snapshots cs
+        JOIN export_scans e ON e.id = cs.scan_id
+        JOIN comments c ON c.id = cs.comment_id
+        JOIN export_posts p ON p.id = c.post_id
+        JOIN export_subs s ON s.id = p.subreddit_id
+    "#),
+    ("post_snapshots", r#"
+        SELECT s.name AS subreddit, e.scan_date, ps.*
+        FROM post_snapshots ps
+        JOIN export_scans e ON e.id = ps.scan_id
+        JOIN export_posts p ON p.id = ps.post_id
+        JOIN export_subs s ON s.id = p.subreddit_id
+    "#),
+    ("comment_snapshots", r#"
+        SELECT s.name AS subreddit, e.scan_date, c.post_id, cs.*
+        FROM comment_snapshots cs
+        JOIN export_scans e ON e.id = cs.scan_id
+        JOIN comments c ON c.id = cs.comment_id
+        JOIN export_posts p ON p.id = c.post_id
+        JOIN export_subs s ON s.id = p.subreddit_id
+    "#),
+    ("subreddit_snapshots", r#"
+        SELECT s.name AS subreddit, e.scan_date, ss.*
+        FROM subreddit_snapshots ss
+        JOIN export_scans e ON e.id = ss.scan_id
+        JOIN export_subs s ON s.id = ss.subreddit_id
+    "#),
+    ("post_metrics", r#"
+        SELECT s.name AS subreddit, e.scan_date, pm.*
+        FROM post_metrics pm
+        JOIN export_scans e ON e.id = pm.scan_id
+        JOIN export_posts p ON p.id = pm.post_id
+        JOIN export_subs s ON s.id = p.subreddit_id
+    "#),
+    ("comment_metrics", r#"
+        SELECT s.name AS subreddit, e.scan_date, cm.*
+        FROM comment_metrics cm
+        JOIN export_scans e ON e.id = cm.scan_id
+        JOIN export_posts p ON p.id = cm.post_id
+        JOIN export_subs s ON s.id = p.subreddit_id
+    "#),
+    ("subreddit_metrics", r#"
+        SELECT s.name AS subreddit, e.scan_date, sm.*
+        FROM subreddit_metrics sm
+        JOIN export_scans e ON e.id = sm.scan_id
+        JOIN export_subs s ON s.id = sm.subreddit_id
+    "#),
+    ("trending", r#"
+        SELECT s.name AS subreddit, e.scan_date, t.*
+        FROM trending t
+        JOIN export_scans e ON e.id = t.scan_id
+        JOIN export_posts p ON p.id = t.post_id
+        JOIN export_subs s ON s.id = t.subreddit_id
+    "#),
+];
+
+// (table, query) for csv: flat files, one row per key. Entity tables hold what the exported
+// scans touched, as it stands at export time; everything per scan is in the snapshot, metric and
+// event tables. Media leave out the inline image data.
+const CSV_TABLES: &[(&str, &str)] = &[
+    ("scans", "SELECT * FROM scans WHERE id IN (SELECT id FROM export_scans)"),
+    ("subreddits", r#"
+        SELECT * FROM export_subs
+        WHERE id IN (SELECT subreddit_id FROM subreddit_snapshots WHERE scan_id IN (SELECT id FROM export_scans))
+           OR id IN (SELECT subreddit_id FROM export_posts WHERE id IN (SELECT post_id FROM export_seen))
+    "#),
+    ("posts", "SELECT * FROM export_posts WHERE id IN (SELECT post_id FROM export_seen)"),
+    ("comments", r#"
+        SELECT * FROM comments
+        WHERE post_id IN (SELECT post_id FROM export_seen)
+          AND id IN (SELECT comment_id FROM comment_snapshots WHERE scan_id IN (SELECT id FROM export_scans))
+    "#),
+    ("media", "SELECT * EXCLUDE (data_base64) FROM media WHERE post_id IN (SELECT post_id FROM export_seen)"),
+    ("subreddit_snapshots", r#"
+        SELECT * FROM subreddit_snapshots
+        WHERE scan_id IN (SELECT id FROM export_scans) AND subreddit_id IN (SELECT id FROM export_subs)
+    "#),
+    ("post_snapshots", r#"
+        SELECT * FROM post_snapshots
+        WHERE scan_id IN (SELECT id FROM export_scans) AND post_id IN (SELECT id FROM export_posts)
+    "#),
+    ("comment_snapshots", r#"
+        SELECT cs.* FROM comment_snapshots cs JOIN comments c ON c.id = cs.comment_id
+        WHERE cs.scan_id IN (SELECT id FROM export_scans) AND c.post_id IN (SELECT id FROM export_posts)
+    "#),
+    ("state_events", r#"
+        SELECT * FROM state_events
+        WHERE scan_id IN (SELECT id FROM export_scans) AND post_id IN (SELECT id FROM export_posts)
+    "#),
+    ("content_versions", r#"
+        SELECT cv.* FROM content_versions cv LEFT JOIN comments c ON cv.kind = 'comment' AND c.id = cv.id
+        WHERE cv.scan_id IN (SELECT id FROM export_scans)
+          AND (CASE WHEN cv.kind = 'post' THEN cv.id ELSE c.post_id END) IN (SELECT id FROM export_posts)
+    "#),
+    ("post_metrics", r#"
+        SELECT * FROM post_metrics
+        WHERE scan_id IN (SELECT id FROM export_scans) AND post_id IN (SELECT id FROM export_posts)
+    "#),
+    ("comment_metrics", r#"
+        SELECT * FROM comment_metrics
+        WHERE scan_id IN (SELECT id FROM export_scans) AND post_id IN (SELECT id FROM export_posts)
+    "#),
+    ("subreddit_metrics", r#"
+        SELECT * FROM subreddit_metrics
+        WHERE scan_id IN (SELECT id FROM export_scans) AND subreddit_id IN (SELECT id FROM export_subs)
+    "#),
+    ("trending", r#"
+        SELECT * FROM trending
+        WHERE scan_id IN (SELECT id FROM export_scans) AND post_id IN (SELECT id FROM export_posts)
+    "#),
+];
+
+fn scan_date(scanned_at: i64) -> String {
+    Utc.timestamp_opt(scanned_at, 0).single()
+        .map(|t| t.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn sql_str(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+// Puts the selected scans in `export_scans` and the subreddit / score filters behind the
+// `export_subs` and `export_posts` views the queries join against; `export_seen` is the
+// filtered posts with a snapshot in those scans.
+fn stage_filters(conn: &Connection, scans: &[(i64, i64)], x: &ExportArgs) -> Result<()> {
+    conn.execute_batch("CREATE OR REPLACE TEMP TABLE export_scans (id BIGINT, scan_date VARCHAR);")?;
+    let mut app = conn.appender("export_scans")?;
+    for (id, at) in scans {
+        app.append_row(params![id, scan_date(*at)])?;
+    }
+    app.flush()?;
+    drop(app);
+
+    let sub = match &x.subreddit {
+        Some(name) => format!("lower(name) = lower({})", sql_str(name.trim_start_matches("r/"))),
+        None => "true".to_string(),
+    };
+    let score = match x.min_score {
+        Some(min) => format!("score >= {min}"),
+        None => "true".to_string(),
+    };
+    conn.execute_batch(&format!(r#"
+    CREATE OR REPLACE TEMP VIEW export_subs AS SELECT * FROM subreddits WHERE {sub};
+    CREATE OR REPLACE TEMP VIEW export_posts AS
+        SELECT * FROM posts WHERE {score} AND subreddit_id IN (SELECT id FROM export_subs);
+    CREATE OR REPLACE TEMP VIEW export_seen AS
+        SELECT DISTINCT post_id FROM post_snapshots
+        WHERE scan_id IN (SELECT id FROM export_scans) AND post_id IN (SELECT id FROM export_posts);
+    "#))?;
+    Ok(())
+}
+
+// Only completed scans are exported.
+// The row filters as recorded in `exports`. Scan ranges aren't part of it: those are tracked per
+// scan.
+fn filter_label(x: &ExportArgs) -> String {
+    let mut parts = vec![];
+    if let Some(name) = &x.subreddit { parts.push(format!("subreddit={}", name.trim_start_matches("r/").to_lowercase())); }
+    if let Some(min) = x.min_score { parts.push(format!("min_score={min}")); }
+    parts.join(",")
+}
+
+// parquet: <out>/<table>/subreddit=<name>/scan_date=<YYYY-MM-DD>/*.parquet. Without --full only
+// scans the directory doesn't have yet (see `exported_scans`) are written, as new files next to
+// the old ones, and only with the same --subreddit / --min-score as the run before; --full
+// rewrites the directory from every scan.
+// ndjson: <out>/posts.ndjson, one post per line with its media, latest metrics and comment tree.
+// csv: <out>/<table>.csv per table, flat (see CSV_TABLES).
+// ndjson and csv cover every scan in --from-scan..--to-scan and replace earlier files.
+pub fn run_export(args: &Args, x: ExportArgs) -> Result<()> {
+    let conn = open_db(&args.db)?;
+    let out = x.out.trim_end_matches('/').to_string();
+    let incremental = x.format == "parquet" && !x.full;
+    let filters = filter_label(&x);
+    if incremental {
+        if let Some(prev) = last_export_filters(&conn, &x.format, &out)? {
+            if prev != filters {
+                return Err(anyhow!(
+                    "{out} was exported with filters [{prev}], not [{filters}]; use --full to rewrite it or another --out"
+                ));
+            }
+        }
+    }
+    let scans: Vec<(i64, i64)> = scans_to_export(&conn, &x.format, incremental.then_some(out.as_str()))?.into_iter()
+        .filter(|(id, _)| x.from_scan.is_none_or(|f| *id >= f) && x.to_scan.is_none_or(|t| *id <= t))
+        .collect();
+    if scans.is_empty() {
+        if incremental { eprintln!("[EXPORT] Nothing new to add to {out}"); }
+        else { eprintln!("[EXPORT] No scans in the selected range"); }
+        return Ok(());
+    }
+    std::fs::create_dir_all(&out)?;
+    stage_filters(&conn, &scans, &x)?;
+
+    let total = match x.format.as_str() {
+        "ndjson" => export_ndjson(&conn, &out)?,
+        "csv" => export_tables(&conn, CSV_TABLES, &out, |dir| format!("{dir}.csv"), "FORMAT CSV, HEADER")?,
+        _ => {
+            let mode = if x.full { "OVERWRITE" } else { "APPEND" };
+            export_tables(&conn, TABLES, &out, |dir| dir.to_string(),
+                &format!("FORMAT PARQUET, PARTITION_BY (subreddit, scan_date), {mode}"))?
+        }
+    };
+    record_export(&conn, &x.format, &out, &filters, &scans, total, !incremental)?;
+    eprintln!("[EXPORT] {} scans ({} to {}), {total} rows into {out}", scans.len(), scans[0].0, scans[scans.len() - 1].0);
+    Ok(())
+}
+
+fn export_tables(
+    conn: &Connection, tables: &[(&str, &str)], out: &str, target: impl Fn(&str) -> String, options: &str,
+) -> Result<usize> {
+    let mut total = 0usize;
+    for (table, query) in tables {
+        let to = target(&format!("{out}/{table}"));
+        let n = conn.execute(&format!("COPY ({query}) TO {} ({options})", sql_str(&to)), [])?;
+        eprintln!("[EXPORT] {table}: {n} rows");
+        total += n;
+    }
+    Ok(total)
+}
+
+fn json_value(v: ValueRef<'_>) -> JsonValue {
+    match v {
+        ValueRef::Null => JsonValue::Null,
+        ValueRef::Boolean(b) => json!(b),
+        ValueRef::TinyInt(n) => json!(n),
+        ValueRef::SmallInt(n) => json!(n),
+        ValueRef::Int(n) => json!(n),
+        ValueRef::BigInt(n) => json!(n),
+        ValueRef::UTinyInt(n) => json!(n),
+        ValueRef::USmallInt(n) => json!(n),
+        ValueRef::UInt(n) => json!(n),
+        ValueRef::UBigInt(n) => json!(n),
+        ValueRef::Float(f) => json!(f),
+        ValueRef::Double(f) => json!(f),
+        ValueRef::Text(t) => json!(String::from_utf8_lossy(t)),
+        other => json!(format!("{other:?}")),
+    }
+}
+
+// Every row of `sql` as a column name -> value object.
+fn query_objects(conn: &Connection, sql: &str, p: &[&dyn duckdb::ToSql]) -> Result<Vec<Map<String, JsonValue>>> {
+    let mut stmt = conn.prepare_cached(sql)?;
+    let mut rows = stmt.query(p)?;
+    let names = rows.as_ref().map(|s| s.column_names()).unwrap_or_default();
+    let mut out = vec![];
+    while let Some(r) = rows.next()? {
+        let mut obj = Map::new();
+        for (i, name) in names.iter().enumerate() {
+            obj.insert(name.clone(), json_value(r.get_ref(i)?));
+        }
+        out.push(obj);
+    }
+    Ok(out)
+}
+
+// Nests comments under their parent via parent_fullname ("t1_<id>" is a comment, anything else
+// the post). Replies whose parent we never stored stay at the top level.
+fn comment_tree(comments: Vec<Map<String, JsonValue>>) -> Vec<JsonValue> {
+    let id_of = |c: &Map<String, JsonValue>| c.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let known: HashSet<String> = comments.iter().map(id_of).collect();
+    let mut children: HashMap<String, Vec<Map<String, JsonValue>>> = HashMap::new();
+    let mut roots = vec![];
+    for c in comments {
+        let parent = c.get("parent_fullname").and_then(|v| v.as_str())
+            .and_then(|p| p.strip_prefix("t1_"))
+            .filter(|p| known.contains(*p))
+            .map(|p| p.to_string());
+        match parent {
+            Some(p) => children.entry(p).or_default().push(c),
+            None => roots.push(c),
+        }
+    }
+    fn build(mut c: Map<String, JsonValue>, children: &mut HashMap<String, Vec<Map<String, JsonValue>>>) -> JsonValue {
+        let id = c.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        let replies = children.remove(&id).unwrap_or_default()
+            .into_iter().map(|r| build(r, children)).collect::<Vec<_>>();
+        c.insert("replies".to_string(), JsonValue::Array(replies));
+        JsonValue::Object(c)
+    }
+    roots.into_iter().map(|c| build(c, &mut children)).collect()
+}
+
+fn export_ndjson(conn: &Connection, out: &str) -> Result<usize> {
+    let posts = query_objects(conn, r#"
+        SELECT s.name AS subreddit, p.*
+        FROM export_posts p
+        JOIN export_subs s ON s.id = p.subreddit_id
+        WHERE p.id IN (SELECT post_id FROM post_snapshots WHERE scan_id IN (SELECT id FROM export_scans))
+        ORDER BY s.name, p.created_utc, p.id
+    "#, &[])?;
+    let path = format!("{out}/posts.ndjson");
+    let mut w = BufWriter::new(File::create(&path)?);
+    let n = posts.len();
+    for mut post in posts {
+        let id = post.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        let media = query_objects(conn,
+            "SELECT * EXCLUDE (post_id, data_base64) FROM media WHERE post_id = ? ORDER BY position", &[&id])?;
+        let metrics = query_objects(conn, r#"
+            SELECT * EXCLUDE (post_id) FROM post_metrics
+            WHERE post_id = ? AND scan_id IN (SELECT id FROM export_scans)
+            ORDER BY scan_id DESC, listing_sort, listing_time LIMIT 1
+        "#, &[&id])?;
+        let comments = query_objects(conn,
+            "SELECT * EXCLUDE (post_id) FROM comments WHERE post_id = ? ORDER BY created_utc, id", &[&id])?;
+        post.insert("media".to_string(), JsonValue::Array(media.into_iter().map(JsonValue::Object).collect()));
+        post.insert("metrics".to_string(), metrics.into_iter().next().map(JsonValue::Object).unwrap_or(JsonValue::Null));
+        post.insert("comments".to_string(), JsonValue::Array(comment_tree(comments)));
+        serde_json::to_writer(&mut w, &post)?;
+        writeln!(w)?;
+    }
+    w.flush()?;
+    eprintln!("[EXPORT] posts.ndjson: {n} posts");
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(id: &str, parent: &str) -> Map<String, JsonValue> {
+        json!({"id": id, "parent_fullname": parent}).as_object().unwrap().clone()
+    }
+
+    // "id[reply,reply]", for readable comparisons
+    fn shape(v: &JsonValue) -> String {
+        let replies: Vec<String> = v["replies"].as_array().unwrap().iter().map(shape).collect();
+        if replies.is_empty() { v["id"].as_str().unwrap().to_string() }
+        else { format!("{}[{}]", v["id"].as_str().unwrap(), replies.join(",")) }
+    }
+
+    #[test]
+    fn comment_tree_nests_replies_in_order() {
+        let tree = comment_tree(vec![
+            comment("a", "t3_p"), comment("b", "t1_a"), comment("c", "t1_b"), comment("d", "t1_a"), comment("e", "t3_p"),
+        ]);
+        assert_eq!(tree.iter().map(shape).collect::<Vec<_>>(), vec!["a[b[c],d]", "e"]);
+    }
+
+    #[test]
+    fn comment_tree_keeps_orphans_at_top_level() {
+        let tree = comment_tree(vec![comment("x", "t1_gone"), comment("y", "t1_x")]);
+        assert_eq!(tree.iter().map(shape).collect::<Vec<_>>(), vec!["x[y]"]);
+    }
+}
@@ -1,8 +1,11 @@
 use thirtyfour::prelude::WebDriver;
 use serde_json::Value;
 use anyhow::Result;
+use std::time::{Duration, Instant};
+use crate::throttle::{gate, Limiter};
+use crate::models::ListingItem;
 
-pub async fn listing_old_top_day(drv: &WebDriver) -> Result<Vec<(String, Option<String>, Option<i64>)>> {
+pub async fn listing_old(drv: &WebDriver) -> Result<Vec<ListingItem>> {
     let js = r#"
     const out=[];
     const els = document.querySelectorAll('div#siteTable div.thing.link');
@@ -26,11 +29,42 @@ pub async fn listing_old_top_day(drv: &WebDriver) -> Result<Vec<(String, Option<
     }).collect())
 }
 
+// Sidebar of the listing page currently loaded. Old reddit doesn't render rules there, so `rules`
+// is left null for the caller to fill from /about/rules (see `page_json`).
+pub async fn sidebar_old(drv: &WebDriver) -> Result<Value> {
+    let js = r#"
+        function text(el){ return el ? (el.textContent||'').trim() : null; }
+        function digits(s){ if(!s) return null; const m=(s.match(/\d[\d,]*/)||[])[0]; return m?parseInt(m.replace(/,/g,'')):null; }
+        const side=document.querySelector('div.side');
+        const res={subscribers:null,users_online:null,created_utc:null,description:null,rules:null,moderators:null};
+        if(!side) return res;
+        res.subscribers=digits(text(side.querySelector('span.subscribers span.number')));
+        res.users_online=digits(text(side.querySelector('p.users-online span.number')));
+        const tm=side.querySelector('span.age time'); if(tm&&tm.dateTime){ res.created_utc=Math.floor(Date.parse(tm.dateTime)/1000); }
+        const meta=document.querySelector('meta[name="description"]');
+        res.description=meta?(meta.getAttribute('content')||'').trim()||null:null;
+        side.querySelectorAll('div.sidecontentbox').forEach(box=>{
+            if(!/moderators/i.test(text(box.querySelector('.title'))||'')) return;
+            res.moderators=[...box.querySelectorAll('ul.content li a[href*="/user/"]')].map(a=>text(a)).filter(Boolean);
+        });
+        return res;
+    "#;
+    let v: Value = drv.execute(js, vec![]).await?.convert()?;
+    Ok(v)
+}
+
+// The document the browser has loaded, parsed as JSON; Chrome shows a .json URL as plain text.
+// None when it isn't JSON (a login wall or an error page).
+pub async fn page_json(drv: &WebDriver) -> Result<Option<Value>> {
+    let text: String = drv.execute("return document.body ? document.body.innerText : '';", vec![]).await?.convert()?;
+    Ok(serde_json::from_str(&text).ok())
+}
+
 pub async fn post_old_page(drv: &WebDriver) -> Result<Value> {
     let js = r#"
         function text(el){ return el ? (el.textContent||'').trim() : null; }
         function digits(s){ if(!s) return null; const m=(s.match(/\d[\d,]*/)||[])[0]; return m?parseInt(m.replace(/,/g,'')):null; }
-        const res={title:null,author:null,score:null,created_utc:null,selftext:null,num_comments:null,images:[],comments:[]};
+        const res={title:null,author:null,score:null,created_utc:null,selftext:null,num_comments:null,flair:null,over_18:null,spoiler:null,stickied:null,locked:null,domain:null,crosspost_parent:null,gilded:null,url:null,upvote_ratio:null,media:[],comments:[],more_stubs:0,continue_links:[]};
         const main=document.querySelector('div#siteTable div.thing.link');
         if(main){
             res.title=text(main.querySelector('a.title'));
@@ -39,33 +73,110 @@ pub async fn post_old_page(drv: &WebDriver) -> Result<Value> {
             const tm=main.querySelector('time'); if(tm&&tm.dateTime){res.created_utc=Math.floor(Date.parse(tm.dateTime)/1000);}
             res.num_comments=digits(text(main.querySelector('a.comments')));
             res.selftext=text(main.querySelector('div.expando div.usertext div.usertext-body'));
-            const imgset=new Set();
-            ['div.expando img','a.thumbnail img','div.expando a[rel="nofollow"] img'].forEach(sel=>{
-                main.querySelectorAll(sel).forEach(img=>{const u=img.getAttribute('src')||''; if(u&&!u.startsWith('data:')) imgset.add(u);});
+            const attr=k=>{ const v=main.getAttribute(k); return (v===null||v==='')?null:v; };
+            const flag=(k,cls)=>{ const v=attr(k); return v!==null ? v==='true' : main.classList.contains(cls); };
+            const fl=main.querySelector('span.linkflairlabel'); res.flair=fl?((fl.getAttribute('title')||'').trim()||text(fl)):null;
+            res.over_18=flag('data-nsfw','over18');
+            res.spoiler=flag('data-spoiler','spoiler');
+            res.stickied=main.classList.contains('stickied');
+            res.locked=main.classList.contains('locked');
+            res.domain=attr('data-domain');
+            res.crosspost_parent=attr('data-crosspost-root-fullname');
+            res.gilded=attr('data-gildings')!==null?parseInt(attr('data-gildings')):null;
+            const du=attr('data-url'); res.url=du?new URL(du, location.href).href:null;
+            const ur=(text(document.querySelector('div.linkinfo div.score'))||'').match(/(\d+)%\s*upvoted/);
+            res.upvote_ratio=ur?parseInt(ur[1])/100:null;
+            // media: gallery items in order, hosted video, direct images, link target; preview images last
+            const add=m=>res.media.push(Object.assign({position:res.media.length,kind:null,url:null,caption:null,mime:null,width:null,height:null,duration:null,fallback_url:null},m));
+            const abs=u=>u?new URL(u, location.href).href:null;
+            const isImg=u=>/\.(jpe?g|png|gif|webp)(\?|$)/i.test(u||'');
+            const seen=new Set();
+            main.querySelectorAll('[class*="gallery"] [class*="gallery-tile"], [class*="gallery"] li').forEach(t=>{
+                const a=t.querySelector('a[href*="redd.it"]'); const img=t.querySelector('img');
+                const u=abs(a?a.getAttribute('href'):(img?img.getAttribute('src'):null));
+                if(!u||seen.has(u)) return; seen.add(u);
+                add({kind:'gallery', url:u, caption:text(t.querySelector('[class*="caption"]'))||null});
             });
-            main.querySelectorAll('div.expando a').forEach(a=>{
-                const h=a.getAttribute('href')||'';
-                if(/\.(jpg|jpeg|png|gif)$/i.test(h)) imgset.add(h);
-            });
-            if(imgset.size===0){
-                document.querySelectorAll('div.content img').forEach(img=>{
-                    const u=img.getAttribute('src')||'';
-                    if(u&&!u.startsWith('data:')&&!/emoji/i.test(u)) imgset.add(u);
-                });
+            const vp=main.querySelector('[data-hls-url], div.reddit-video-player-root');
+            if(vp){
+                const v=main.querySelector('video');
+                const dur=v&&isFinite(v.duration)?Math.round(v.duration):null;
+                add({kind:'video', url:res.url, duration:dur,
+                     width:v&&v.videoWidth?v.videoWidth:null, height:v&&v.videoHeight?v.videoHeight:null,
+                     fallback_url:abs(vp.getAttribute('data-hls-url')||(v&&(v.currentSrc||v.getAttribute('src'))))});
+            }
+            if(res.media.length===0 && isImg(res.url)){ seen.add(res.url); add({kind:'image', url:res.url}); }
+            const isSelf=(res.domain||'').startsWith('self.');
+            if(res.media.length===0 && res.url && !isSelf && !/reddit\.com\/(gallery|r\/)/.test(res.url)){
+                add({kind:'link', url:res.url});
             }
-            res.images=Array.from(imgset);
+            main.querySelectorAll('div.expando img, a.thumbnail img').forEach(img=>{
+                const u=abs(img.getAttribute('src')); if(!u||u.startsWith('data:')||seen.has(u)) return;
+                seen.add(u); add({kind:'preview', url:u, width:img.naturalWidth||null, height:img.naturalHeight||null});
+            });
         }
         document.querySelectorAll('div.sitetable.nestedlisting div.thing.comment').forEach(c=>{
             const fn=c.getAttribute('data-fullname')||''; const id=fn.startsWith('t1_')?fn.slice(3):null; if(!id) return;
             const parent=c.getAttribute('data-parent')||null;
             const author=text(c.querySelector('a.author'));
-            const s1=text(c.querySelector('span.score.unvoted'))||text(c.querySelector('span.score')); const score=digits(s1);
+            const entry=c.querySelector(':scope > div.entry');
+            const own=sel=>entry?entry.querySelector(sel):null;
+            const score_hidden=!!own('span.score-hidden');
+            const s1=text(own('span.score.unvoted'))||text(own('span.score')); const score=score_hidden?null:digits(s1);
             let created_utc=null; const tm=c.querySelector('time'); if(tm&&tm.dateTime){ created_utc=Math.floor(Date.parse(tm.dateTime)/1000); }
             const body=text(c.querySelector('div.entry div.usertext-body'));
-            res.comments.push({id, parent_fullname:parent, author, body, score, created_utc});
+            let depth=0; for(let p=c.parentElement;p;p=p.parentElement){ if(p.matches&&p.matches('div.thing.comment')) depth++; }
+            const pl=c.getAttribute('data-permalink'); const permalink=pl?new URL(pl, location.href).href:null;
+            const et=own('time.edited-timestamp'); const edited=!!et;
+            const edited_utc=(et&&et.dateTime)?Math.floor(Date.parse(et.dateTime)/1000):null;
+            const au=own('a.author'); const distinguished=au?(au.classList.contains('admin')?'admin':(au.classList.contains('moderator')?'moderator':null)):null;
+            const stickied=c.classList.contains('stickied');
+            res.comments.push({id, parent_fullname:parent, author, body, score, created_utc,
+                               depth, permalink, edited, edited_utc, distinguished, stickied, score_hidden});
         });
+        res.more_stubs=document.querySelectorAll('div.sitetable.nestedlisting span.morecomments a').length;
+        res.continue_links=Array.from(document.querySelectorAll('div.sitetable.nestedlisting span.deepthread > a')).map(a=>a.href);
         return res;
     "#;
     let v: Value = drv.execute(js, vec![]).await?.convert()?;
     Ok(v)
 }
+
+// Clicks "load more comments" stubs one at a time until none are left or `max` comments
+// are on the page. Each click is an XHR to /api/morechildren, so it goes through the limiter.
+// Returns false if a stub never resolved or the cap was hit first.
+pub async fn expand_old_morecomments(drv: &WebDriver, limiter: &Limiter, max: usize) -> Result<bool> {
+    let click = r#"
+        const a=document.querySelector('div.sitetable.nestedlisting span.morecomments a:not([data-th-tried])');
+        if(!a) return false;
+        a.setAttribute('data-th-tried','1'); a.click(); return true;
+    "#;
+    let pending = "return document.querySelectorAll('span.morecomments a[data-th-tried]:not([data-th-failed])').length;";
+    let count = "return document.querySelectorAll('div.sitetable.nestedlisting div.thing.comment').length;";
+    let stubs = "return document.querySelectorAll('div.sitetable.nestedlisting span.morecomments a').length;";
+
+    let mut complete = true;
+    loop {
+        let n: usize = drv.execute(count, vec![]).await?.convert()?;
+        if n >= max {
+            let left: usize = drv.execute(stubs, vec![]).await?.convert()?;
+            return Ok(complete && left == 0);
+        }
+        gate(limiter).await;
+        let clicked: bool = drv.execute(click, vec![]).await?.convert()?;
+        if !clicked { return Ok(complete); }
+
+        // old.reddit swaps the stub for the loaded children; a stub still there after 10s failed
+        let started = Instant::now();
+        loop {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            let left: usize = drv.execute(pending, vec![]).await?.convert()?;
+            if left == 0 { break; }
+            if started.elapsed() > Duration::from_secs(10) {
+                complete = false;
+                drv.execute("document.querySelectorAll('span.morecomments a[data-th-tried]').forEach(a=>a.setAttribute('data-th-failed','1'));", vec![]).await?;
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::cli::{Args, HistoryArgs};
+use crate::db::{content_history, open_db, state_history, thing_kind};
+use crate::utils::fmt_ts;
+
+use anyhow::{Result, anyhow};
+
+// Prints text versions and deletion/removal events for one id, oldest first.
+pub fn run_history(args: &Args, h: HistoryArgs) -> Result<()> {
+    let id = h.id.trim_start_matches("t1_").trim_start_matches("t3_");
+    let conn = open_db(&args.db)?;
+    let kind = thing_kind(&conn, id)?.ok_or_else(|| anyhow!("{id}: not in {}", args.db))?;
+
+    // (when, scan, line, text)
+    let mut timeline: Vec<(i64, i64, String, Option<String>)> = vec![];
+    let versions = content_history(&conn, id)?;
+    for (i, (seen, scan, edited, hash, body)) in versions.iter().enumerate() {
+        let what = if i == 0 { "first seen" } else { "edited" };
+        let when = edited.map(|e| format!(", edited {}", fmt_ts(e))).unwrap_or_default();
+        timeline.push((*seen, *scan, format!("{what} [{}]{when}", &hash[..12]), body.clone()));
+    }
+    for (at, scan, old, new) in state_history(&conn, id)? {
+        let from = old.unwrap_or_else(|| "unseen".to_string());
+        timeline.push((at, scan, format!("{from} -> {new}"), None));
+    }
+    timeline.sort_by_key(|t| (t.0, t.1));
+
+    println!("{kind} {id}: {} version(s)", versions.len());
+    for (at, scan, line, body) in timeline {
+        println!("{}  scan {scan}  {line}", fmt_ts(at));
+        if let Some(b) = body {
+            for l in b.lines() { println!("    {l}"); }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,273 @@
+use crate::api::make_client;
+use crate::blobs::store_blob;
+use crate::cli::Args;
+use crate::db::content_hash;
+use crate::models::ImageFetch;
+use crate::throttle::{make_host_limiter, HostLimiter};
+use crate::utils::{dhash, image_dims, to_b64};
+
+use anyhow::Result;
+use backoff::{ExponentialBackoff, backoff::Backoff};
+use reqwest::{Client, StatusCode};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+#[derive(Clone)]
+pub struct ImagePoolOpts {
+    pub mode: String,
+    pub blob_dir: String,
+    pub workers: usize,
+    pub rpm_per_host: u32,
+    pub max_bytes: u64,
+    pub mimes: Vec<String>,
+    pub retries: u32,
+    pub timeout_secs: u64,
+}
+
+impl ImagePoolOpts {
+    pub fn from_args(args: &Args) -> ImagePoolOpts {
+        ImagePoolOpts {
+            mode: args.images.clone(),
+            blob_dir: args.blob_dir.clone(),
+            workers: args.image_workers.max(1),
+            rpm_per_host: args.image_rpm_per_host,
+            max_bytes: args.image_max_bytes,
+            mimes: args.image_mimes.split(',').map(|m| m.trim().to_lowercase()).filter(|m| !m.is_empty()).collect(),
+            retries: args.image_retries.max(1),
+            timeout_secs: args.image_timeout,
+        }
+    }
+}
+
+pub struct ImageJob {
+    pub post_id: String,
+    pub position: i64,
+    pub url: String,
+    // the page worker's proxy, so images leave from the same address
+    pub proxy: Option<String>,
+}
+
+// Cheap to clone into every page worker; a no-op when images are off.
+#[derive(Clone)]
+pub struct ImageQueue(Option<mpsc::Sender<ImageJob>>);
+
+impl ImageQueue {
+    // Waits only when the queue is full, which is the pool's backpressure on the crawl.
+    pub async fn submit(&self, job: ImageJob) {
+        if let Some(tx) = &self.0 {
+            let _ = tx.send(job).await;
+        }
+    }
+}
+
+struct Shared {
+    opts: ImagePoolOpts,
+    hosts: HostLimiter,
+    // one client per proxy, reused by every download through it
+    clients: Mutex<HashMap<Option<String>, Client>>,
+    ok: AtomicUsize,
+    failed: AtomicUsize,
+    // set once `on_done` can't deliver; the rest of the queue is dropped unfetched
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn client(&self, proxy: Option<&str>) -> Result<Client> {
+        let mut m = self.clients.lock().unwrap();
+        let key = proxy.map(|p| p.to_string());
+        if let Some(c) = m.get(&key) { return Ok(c.clone()); }
+        let c = make_client(proxy, 0, Duration::from_secs(self.opts.timeout_secs))?;
+        m.insert(key, c.clone());
+        Ok(c)
+    }
+}
+
+// Downloads post images off the page workers' path: a bounded queue feeding `workers` tasks,
+// each result handed to `on_done` (the DB writer), which returns false once the writer is gone.
+pub struct ImagePool {
+    tx: Option<mpsc::Sender<ImageJob>>,
+    tasks: JoinSet<()>,
+    shared: Arc<Shared>,
+}
+
+impl ImagePool {
+    pub fn start<F, Fut>(opts: ImagePoolOpts, on_done: F) -> ImagePool
+    where
+        F: Fn(ImageFetch) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send,
+    {
+        let shared = Arc::new(Shared {
+            hosts: make_host_limiter(opts.rpm_per_host),
+            clients: Mutex::new(HashMap::new()),
+            ok: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            opts,
+        });
+        let mut tasks = JoinSet::new();
+        if shared.opts.mode == "none" {
+            return ImagePool { tx: None, tasks, shared };
+        }
+
+        let (tx, rx) = mpsc::channel::<ImageJob>(shared.opts.workers * 64);
+        let rx = Arc::new(tokio::sync::Mutex::new(rx));
+        let on_done = Arc::new(on_done);
+        for _ in 0..shared.opts.workers {
+            let rx = rx.clone();
+            let shared = shared.clone();
+            let on_done = on_done.clone();
+            tasks.spawn(async move {
+                loop {
+                    let job = rx.lock().await.recv().await;
+                    let Some(job) = job else { break };
+                    if shared.closed.load(Ordering::Relaxed) { continue; }
+                    let f = fetch_job(&shared, job).await;
+                    if f.error.is_some() { shared.failed.fetch_add(1, Ordering::Relaxed); }
+                    else { shared.ok.fetch_add(1, Ordering::Relaxed); }
+                    if !on_done(f).await {
+                        // refuse new jobs; what is already queued drains through the check above
+                        shared.closed.store(true, Ordering::Relaxed);
+                        rx.lock().await.close();
+                    }
+                }
+            });
+        }
+        ImagePool { tx: Some(tx), tasks, shared }
+    }
+
+    pub fn queue(&self) -> ImageQueue {
+        ImageQueue(self.tx.clone())
+    }
+
+    // Drains the queue; returns (downloaded, failed).
+    pub async fn finish(mut self) -> (usize, usize) {
+        self.tx.take();
+        while self.tasks.join_next().await.is_some() {}
+        (self.shared.ok.load(Ordering::Relaxed), self.shared.failed.load(Ordering::Relaxed))
+    }
+}
+
+enum Fail {
+    Retry(String),
+    Fatal(String),
+}
+
+fn allowed(o: &ImagePoolOpts, mime: &str) -> bool {
+    o.mimes.iter().any(|m| m == mime)
+}
+
+async fn download(client: &Client, url: &str, o: &ImagePoolOpts) -> std::result::Result<(Vec<u8>, String), Fail> {
+    let mut resp = client.get(url).send().await.map_err(|e| {
+        Fail::Retry(if e.is_timeout() { "timeout".to_string() } else { format!("request: {e}") })
+    })?;
+    let status = resp.status();
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        return Err(Fail::Retry(format!("http {}", status.as_u16())));
+    }
+    if !status.is_success() {
+        return Err(Fail::Fatal(format!("http {}", status.as_u16())));
+    }
+    if let Some(n) = resp.content_length().filter(|&n| n > o.max_bytes) {
+        return Err(Fail::Fatal(format!("too large ({n} bytes)")));
+    }
+    // octet-stream says nothing, so sniff the bytes instead
+    let header_mime = resp.headers().get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.split(';').next().unwrap_or(s).trim().to_lowercase())
+        .filter(|m| m != "application/octet-stream");
+    if let Some(m) = header_mime.as_deref().filter(|m| !allowed(o, m)) {
+        return Err(Fail::Fatal(format!("mime {m} not allowed")));
+    }
+
+    let mut bytes = vec![];
+    loop {
+        let chunk = resp.chunk().await.map_err(|e| {
+            Fail::Retry(if e.is_timeout() { "timeout".to_string() } else { format!("body: {e}") })
+        })?;
+        let Some(chunk) = chunk else { break };
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() as u64 > o.max_bytes {
+            return Err(Fail::Fatal(format!("too large (over {} bytes)", o.max_bytes)));
+        }
+    }
+
+    let mime = header_mime.or_else(|| image::guess_format(&bytes).ok().map(|f| f.to_mime_type().to_string()));
+    match mime {
+        Some(m) if allowed(o, &m) => Ok((bytes, m)),
+        Some(m) => Err(Fail::Fatal(format!("mime {m} not allowed"))),
+        None => Err(Fail::Fatal("unknown mime".to_string())),
+    }
+}
+
+fn blank(post_id: &str, position: i64, url: &str) -> ImageFetch {
+    ImageFetch {
+        post_id: post_id.to_string(), position, url: url.to_string(),
+        mime: None, size_bytes: None, width: None, height: None,
+        data_base64: None, content_hash: None, phash: None, error: None,
+    }
+}
+
+async fn fetch_job(shared: &Shared, job: ImageJob) -> ImageFetch {
+    let mut f = blank(&job.post_id, job.position, &job.url);
+    let o = &shared.opts;
+    let client = match shared.client(job.proxy.as_deref()) {
+        Ok(c) => c,
+        Err(e) => { f.error = Some(format!("client: {e}")); return f; }
+    };
+    let host = reqwest::Url::parse(&f.url).ok().and_then(|u| u.host_str().map(|h| h.to_string())).unwrap_or_default();
+
+    let mut eb = ExponentialBackoff {
+        current_interval: Duration::from_millis(500),
+        initial_interval: Duration::from_millis(500),
+        max_interval:     Duration::from_secs(8),
+        max_elapsed_time: None,
+        ..ExponentialBackoff::default()
+    };
+    let mut got = None;
+    for i in 0..o.retries {
+        shared.hosts.until_key_ready(&host).await;
+        match download(&client, &f.url, o).await {
+            Ok(r) => { got = Some(r); break; }
+            Err(Fail::Fatal(reason)) => { f.error = Some(reason); break; }
+            Err(Fail::Retry(reason)) => {
+                f.error = Some(format!("{reason} (after {} attempts)", i + 1));
+                if i + 1 < o.retries {
+                    tokio::time::sleep(eb.next_backoff().unwrap_or(Duration::from_secs(2))).await;
+                }
+            }
+        }
+    }
+    let Some((bytes, mime)) = got else { return f };
+    f.error = None;
+    let mut failed = blank(&f.post_id, f.position, &f.url);
+
+    // hashing and decoding are CPU work; keep them off the async threads
+    let mode = o.mode.clone();
+    let blob_dir = o.blob_dir.clone();
+    let done = tokio::task::spawn_blocking(move || -> Result<ImageFetch> {
+        if mode == "blob" {
+            f.content_hash = Some(store_blob(&blob_dir, &bytes)?);
+        } else {
+            f.content_hash = Some(content_hash(&bytes));
+            f.data_base64 = Some(to_b64(&bytes));
+        }
+        f.mime = Some(mime);
+        f.size_bytes = Some(bytes.len() as i64);
+        if let Some((w, h)) = image_dims(&bytes) {
+            f.width = Some(w);
+            f.height = Some(h);
+        }
+        f.phash = dhash(&bytes);
+        Ok(f)
+    }).await;
+    match done {
+        Ok(Ok(f)) => f,
+        Ok(Err(e)) => { failed.error = Some(format!("store: {e}")); failed }
+        Err(e) => { failed.error = Some(format!("worker: {e}")); failed }
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser;
 use indicatif::ProgressBar;
 use std::time::Duration;
@@ -12,26 +12,41 @@ mod db;
 mod models;
 mod crawler;
 mod utils;
-
-use crate::cli::Args;
-use crate::crawler::run_crawl;
-use crate::db::{compute_comment_metrics, compute_post_metrics, open_db, start_scan};
+mod api;
+mod backend;
+mod watch;
+mod history;
+mod blobs;
+mod reposts;
+mod images;
+mod replay;
+mod export;
+mod metrics;
+mod stats;
+mod prune;
+mod virality;
+
+use crate::blobs::run_gc;
+use crate::cli::{Args, Command, RefreshArgs};
+use crate::crawler::{close_scan, run_crawl, run_refresh};
+use crate::db::{open_db, start_scan};
+use crate::export::run_export;
+use crate::history::run_history;
+use crate::metrics::{run_backfill, run_metrics};
 use crate::nav::PoliteKnobs;
-use crate::throttle::make_limiter;
+use crate::prune::run_prune;
+use crate::replay::run_replay;
+use crate::reposts::run_reposts;
+use crate::stats::run_stats;
+use crate::throttle::{make_limiter, Limiter};
+use crate::virality::Ranking;
+use crate::watch::run_watch;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
 
-    let db_path = args.db.clone();
-
-
-    let conn = open_db(&db_path)?;
-    let scan_id = start_scan(&conn)?;
-    drop(conn);
-
-
     let limiter = make_limiter(args.rpm);
 
 
@@ -44,21 +59,48 @@ async fn main() -> Result<()> {
     };
 
 
+    // crawl, refresh and watch drive the browser; the rest only touch the DB
+    match args.command.clone().unwrap_or(Command::Crawl) {
+        Command::Crawl => scan(args, None, limiter, knobs).await,
+        Command::Refresh(r) => scan(args, Some(r), limiter, knobs).await,
+        Command::Watch(w) => run_watch(args, w, limiter, knobs).await,
+        Command::Metrics(m) => run_metrics(&args, m),
+        Command::Backfill => run_backfill(&args),
+        Command::Stats => run_stats(&args),
+        Command::Export(x) => run_export(&args, x),
+        Command::Prune(p) => run_prune(&args, p),
+        Command::History(h) => run_history(&args, h),
+        Command::Gc(g) => run_gc(&args, g),
+        Command::Reposts(r) => run_reposts(&args, r),
+        Command::Replay(r) => run_replay(&args, r).await,
+    }
+}
+
+async fn scan(args: Args, refresh: Option<RefreshArgs>, limiter: Limiter, knobs: PoliteKnobs) -> Result<()> {
+    let db_path = args.db.clone();
+    let ranking = Ranking::from_args(&args)?;
+    if args.workers == 0 { return Err(anyhow!("--workers must be at least 1")); }
+
+
+    let conn = open_db(&db_path)?;
+    let scan_id = start_scan(&conn, &args)?;
+    drop(conn);
+
+
     let pb = ProgressBar::new_spinner();
     pb.enable_steady_tick(Duration::from_millis(120));
     pb.set_message("Launching workers...");
 
 
-    let saved = run_crawl(args, limiter, knobs, scan_id).await?;
+    let res = match refresh {
+        Some(r) => run_refresh(args, r, limiter, knobs, scan_id).await,
+        None => run_crawl(args, limiter, knobs, scan_id).await,
+    };
     pb.finish_and_clear();
 
 
-    let conn = open_db(&db_path)?;
-    eprintln!("[METRICS] Computing post metrics...");
-    compute_post_metrics(&conn, scan_id)?;
-    eprintln!("[METRICS] Computing comment metrics...");
-    compute_comment_metrics(&conn, scan_id)?;
-    eprintln!("[SCAN {scan_id}] Saved {saved} posts");
+    let stats = close_scan(&db_path, scan_id, &ranking, res)?;
+    eprintln!("[SCAN {scan_id}] Saved {}", stats.summary());
 
     Ok(())
 }
@@ -0,0 +1,46 @@
+use crate::cli::{Args, MetricsArgs};
+use crate::db::{compute_metrics, in_tx, open_db, scans_after};
+use crate::virality::Ranking;
+
+use anyhow::{anyhow, Result};
+
+pub fn run_metrics(args: &Args, m: MetricsArgs) -> Result<()> {
+    let ranking = Ranking::from_args(args)?;
+    let conn = open_db(&args.db)?;
+    let all: Vec<i64> = scans_after(&conn, 0)?.into_iter().map(|(id, _)| id).collect();
+    let scans = if m.scan == "all" {
+        all
+    } else {
+        let id: i64 = m.scan.parse().map_err(|_| anyhow!("--scan takes a scan id or \"all\", got {:?}", m.scan))?;
+        if !all.contains(&id) { return Err(anyhow!("no scan {id} in {}", args.db)); }
+        vec![id]
+    };
+    for id in &scans {
+        eprintln!("[SCAN {id}]");
+        compute_metrics(&conn, *id, &ranking)?;
+    }
+    eprintln!("[METRICS] Recomputed {} scan(s)", scans.len());
+    Ok(())
+}
+
+// All-or-nothing: the metrics only change once every scan has been recomputed.
+pub fn run_backfill(args: &Args) -> Result<()> {
+    let ranking = Ranking::from_args(args)?;
+    let conn = open_db(&args.db)?;
+    let mut stmt = conn.prepare("SELECT id FROM scans WHERE status = 'completed' ORDER BY id")?;
+    let scans = stmt.query_map([], |r| r.get::<_, i64>(0))?.collect::<std::result::Result<Vec<_>, _>>()?;
+
+    in_tx(&conn, |c| {
+        for id in &scans {
+            eprintln!("[SCAN {id}]");
+            compute_metrics(c, *id, &ranking)?;
+        }
+        Ok(())
+    })?;
+
+    let (rows, timed): (i64, i64) = conn.query_row(
+        "SELECT count(*), count(*) FILTER (WHERE dt_seconds > 0) FROM post_metrics", [], |r| Ok((r.get(0)?, r.get(1)?))
+    )?;
+    eprintln!("[BACKFILL] Recomputed {} scans; {timed} of {rows} post_metrics rows have a previous scan to compare with", scans.len());
+    Ok(())
+}
@@ -1,6 +1,58 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+
+pub const SORTS: &[&str] = &["hot", "new", "rising", "controversial", "top"];
+pub const WINDOWS: &[&str] = &["hour", "day", "week", "month", "year", "all"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Listing {
+    pub sort: String,
+    // only top/controversial take a time window
+    pub time: Option<String>,
+}
+
+impl Listing {
+    pub fn new(sort: &str, time: &str) -> Self {
+        let sort = sort.trim().to_lowercase();
+        let time = if sort == "top" || sort == "controversial" {
+            Some(time.trim().to_lowercase())
+        } else {
+            None
+        };
+        Listing { sort, time }
+    }
+
+    pub fn url(&self, sub: &str) -> String {
+        match &self.time {
+            Some(t) => format!("https://old.reddit.com/r/{}/{}/?t={}", sub, self.sort, t),
+            None => format!("https://old.reddit.com/r/{}/{}/", sub, self.sort),
+        }
+    }
+
+    // listing_time as stored: key columns can't be NULL, so no window is ''
+    pub fn key_time(&self) -> &str {
+        self.time.as_deref().unwrap_or("")
+    }
+
+    pub fn label(&self) -> String {
+        match &self.time {
+            Some(t) => format!("{}/{}", self.sort, t),
+            None => self.sort.clone(),
+        }
+    }
+}
+
+// (post id, comments href, created_utc) as read off a listing page
+pub type ListingItem = (String, Option<String>, Option<i64>);
 
 #[derive(Debug, Clone)]
+pub struct SubTarget {
+    pub name: String,
+    pub listing: Listing,
+    // watch mode: rescan every N minutes instead of on the default schedule
+    pub interval_mins: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PostRow {
     pub id: String,
     pub url: String,
@@ -10,25 +62,163 @@ pub struct PostRow {
     pub created_utc: Option<i64>,
     pub selftext: Option<String>,
     pub num_comments: Option<i64>,
+    pub flair: Option<String>,
+    pub over_18: Option<bool>,
+    pub spoiler: Option<bool>,
+    pub stickied: Option<bool>,
+    pub locked: Option<bool>,
+    pub domain: Option<String>,
+    pub crosspost_parent: Option<String>,
+    pub gilded: Option<i64>,
+    pub outbound_url: Option<String>,
+    pub upvote_ratio: Option<f64>,
+    // live / deleted / removed, see content_state
+    #[serde(deserialize_with = "de_state")]
+    pub state: State,
 }
 
-#[derive(Debug, Clone)]
-pub struct CommentRow {
-    pub id: String,
+// Outcome of downloading one media item, applied to its row once the image pool is done with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageFetch {
     pub post_id: String,
-    pub parent_fullname: Option<String>,
-    pub author: Option<String>,
-    pub body: Option<String>,
+    pub position: i64,
+    pub url: String,
+    pub mime: Option<String>,
+    pub size_bytes: Option<i64>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub data_base64: Option<String>,
+    // SHA-256 of the downloaded bytes; names the file in --images blob mode
+    pub content_hash: Option<String>,
+    // utils::dhash of the downloaded image, bits stored as i64
+    pub phash: Option<i64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubredditSnapshot {
+    pub subreddit: String,
+    pub scan_id: i64,
+    pub subscribers: Option<i64>,
+    pub users_online: Option<i64>,
+    pub created_utc: Option<i64>,
+    pub description: Option<String>,
+    // JSON arrays of rule titles / moderator names
+    pub rules: Option<String>,
+    pub moderators: Option<String>,
+}
+
+// One media item of a post, in display order. kind: gallery / video / image / link / preview
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaRow {
+    pub position: i64,
+    pub kind: String,
+    pub url: String,
+    pub caption: Option<String>,
+    pub mime: Option<String>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub duration_secs: Option<f64>,
+    pub fallback_url: Option<String>,
+}
+
+impl MediaRow {
+    // the kinds that are still images we can download
+    pub fn is_image(&self) -> bool {
+        matches!(self.kind.as_str(), "gallery" | "image" | "preview")
+            && !self.mime.as_deref().is_some_and(|m| m.starts_with("video/"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostSnapshot {
+    pub post_id: String,
+    pub scan_id: i64,
+    pub score: Option<i64>,
+    pub num_comments: Option<i64>,
+    pub created_utc: Option<i64>,
+    pub listing: Listing,
+    // the post fields that can change between scans
+    pub upvote_ratio: Option<f64>,
+    pub flair: Option<String>,
+    pub over_18: Option<bool>,
+    pub spoiler: Option<bool>,
+    pub stickied: Option<bool>,
+    pub locked: Option<bool>,
+    pub gilded: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommentSnapshot {
+    pub comment_id: String,
+    pub scan_id: i64,
     pub score: Option<i64>,
     pub created_utc: Option<i64>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct JsPost {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommentRow {
     pub id: String,
+    pub post_id: String,
     pub parent_fullname: Option<String>,
     pub author: Option<String>,
     pub body: Option<String>,
     pub score: Option<i64>,
     pub created_utc: Option<i64>,
+    pub depth: Option<i64>,
+    pub permalink: Option<String>,
+    pub edited: Option<bool>,
+    pub edited_utc: Option<i64>,
+    // "moderator" / "admin" when the comment is distinguished
+    pub distinguished: Option<String>,
+    pub stickied: Option<bool>,
+    // "[score hidden]" rather than an unknown score
+    pub score_hidden: Option<bool>,
+    #[serde(deserialize_with = "de_state")]
+    pub state: State,
+}
+
+// "[deleted]" is the author's doing, "[removed]" a moderator's or admin's. `removed_by` is the
+// JSON `removed_by_category`, which also covers link posts that have no body to blank out.
+pub fn content_state(author: Option<&str>, body: Option<&str>, removed_by: Option<&str>) -> &'static str {
+    match (removed_by, body) {
+        (Some("deleted"), _) | (_, Some("[deleted]")) => "deleted",
+        (Some(_), _) | (_, Some("[removed]")) => "removed",
+        _ if author == Some("[deleted]") && body.is_none() => "deleted",
+        _ => "live",
+    }
+}
+
+// Dead-letter files store the state as text; map it back onto the content_state values.
+// (The alias keeps serde from treating the field as a borrow of the input.)
+pub type State = &'static str;
+
+fn de_state<'de, D: Deserializer<'de>>(d: D) -> Result<State, D::Error> {
+    Ok(match String::deserialize(d)?.as_str() {
+        "deleted" => "deleted",
+        "removed" => "removed",
+        _ => "live",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_state_reads_placeholders() {
+        assert_eq!(content_state(Some("alice"), Some("hi"), None), "live");
+        assert_eq!(content_state(Some("[deleted]"), Some("[deleted]"), None), "deleted");
+        assert_eq!(content_state(Some("[deleted]"), Some("[removed]"), None), "removed");
+        // a link post has no body; a deleted author alone marks it deleted
+        assert_eq!(content_state(Some("[deleted]"), None, None), "deleted");
+        assert_eq!(content_state(None, None, None), "live");
+    }
+
+    #[test]
+    fn content_state_uses_removed_by() {
+        assert_eq!(content_state(Some("alice"), None, Some("moderator")), "removed");
+        assert_eq!(content_state(Some("alice"), Some("hi"), Some("deleted")), "deleted");
+        assert_eq!(content_state(Some("[deleted]"), Some("[deleted]"), Some("moderator")), "deleted");
+    }
 }
@@ -0,0 +1,88 @@
+use crate::cli::{Args, PruneArgs};
+use crate::db::{open_db, scans_after};
+use crate::utils::now_secs;
+
+use anyhow::{anyhow, Result};
+use duckdb::{params, Connection};
+
+// Run in order against `prune_scans`. Posts first seen and last seen inside the pruned scans go
+// too (`prune_posts`), with their comments, media and history; posts still in a kept scan stay.
+const STEPS: &[(&str, &str)] = &[
+    ("content_versions", r#"DELETE FROM content_versions WHERE id IN (SELECT id FROM prune_posts)
+        OR id IN (SELECT id FROM comments WHERE post_id IN (SELECT id FROM prune_posts))"#),
+    ("state_events", "DELETE FROM state_events WHERE post_id IN (SELECT id FROM prune_posts)"),
+    ("media", "DELETE FROM media WHERE post_id IN (SELECT id FROM prune_posts)"),
+    ("post_snapshots", "DELETE FROM post_snapshots WHERE scan_id IN (SELECT id FROM prune_scans)"),
+    ("comment_snapshots", "DELETE FROM comment_snapshots WHERE scan_id IN (SELECT id FROM prune_scans)"),
+    ("subreddit_snapshots", "DELETE FROM subreddit_snapshots WHERE scan_id IN (SELECT id FROM prune_scans)"),
+    ("post_metrics", "DELETE FROM post_metrics WHERE scan_id IN (SELECT id FROM prune_scans)"),
+    ("comment_metrics", "DELETE FROM comment_metrics WHERE scan_id IN (SELECT id FROM prune_scans)"),
+    ("trending", "DELETE FROM trending WHERE scan_id IN (SELECT id FROM prune_scans)"),
+    ("subreddit_metrics", "DELETE FROM subreddit_metrics WHERE scan_id IN (SELECT id FROM prune_scans)"),
+    ("comments", "DELETE FROM comments WHERE post_id IN (SELECT id FROM prune_posts)"),
+    ("posts", "DELETE FROM posts WHERE id IN (SELECT id FROM prune_posts)"),
+    ("scans", "DELETE FROM scans WHERE id IN (SELECT id FROM prune_scans)"),
+];
+
+// With both options a scan has to be old enough and outside the newest N.
+fn pick_scans(conn: &Connection, p: &PruneArgs) -> Result<Vec<i64>> {
+    let scans = scans_after(conn, 0)?;
+    let cutoff = p.older_than_days.map(|d| now_secs() - d * 86400);
+    let keep_from = p.keep_scans.map_or(scans.len(), |n| scans.len().saturating_sub(n));
+    Ok(scans.iter().enumerate()
+        .filter(|(i, (_, at))| *i < keep_from && cutoff.is_none_or(|c| *at < c))
+        .map(|(_, (id, _))| *id)
+        .collect())
+}
+
+fn delete(conn: &Connection, ids: &[i64]) -> Result<Vec<(&'static str, usize)>> {
+    conn.execute_batch("CREATE OR REPLACE TEMP TABLE prune_scans (id BIGINT);")?;
+    let mut app = conn.appender("prune_scans")?;
+    for id in ids {
+        app.append_row(params![id])?;
+    }
+    app.flush()?;
+    drop(app);
+    conn.execute_batch(r#"
+    CREATE OR REPLACE TEMP TABLE prune_posts AS
+        SELECT DISTINCT post_id AS id FROM post_snapshots WHERE scan_id IN (SELECT id FROM prune_scans)
+        EXCEPT
+        SELECT DISTINCT post_id FROM post_snapshots WHERE scan_id NOT IN (SELECT id FROM prune_scans);
+    "#)?;
+    let mut counts = vec![];
+    for (table, sql) in STEPS {
+        counts.push((*table, conn.execute(sql, [])?));
+    }
+    Ok(counts)
+}
+
+pub fn run_prune(args: &Args, p: PruneArgs) -> Result<()> {
+    if p.older_than_days.is_none() && p.keep_scans.is_none() {
+        return Err(anyhow!("prune needs --older-than-days and/or --keep-scans"));
+    }
+    let conn = open_db(&args.db)?;
+    let ids = pick_scans(&conn, &p)?;
+    if ids.is_empty() {
+        eprintln!("[PRUNE] No scans to prune");
+        return Ok(());
+    }
+
+    // a dry run deletes inside the transaction and rolls it back to get the counts
+    conn.execute_batch("BEGIN TRANSACTION;")?;
+    let counts = match delete(&conn, &ids) {
+        Ok(c) => c,
+        Err(e) => { let _ = conn.execute_batch("ROLLBACK;"); return Err(e); }
+    };
+    conn.execute_batch(if p.dry_run { "ROLLBACK;" } else { "COMMIT;" })?;
+
+    let verb = if p.dry_run { "Would delete" } else { "Deleted" };
+    eprintln!("[PRUNE] {verb} {} scans ({} to {})", ids.len(), ids[0], ids[ids.len() - 1]);
+    for (table, n) in counts.iter().filter(|(_, n)| *n > 0) {
+        eprintln!("[PRUNE]   {table}: {n} rows");
+    }
+    if !p.dry_run {
+        conn.execute_batch("CHECKPOINT;")?;
+        eprintln!("[PRUNE] Run `gc` to delete image blobs no post references any more");
+    }
+    Ok(())
+}
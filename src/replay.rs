@@ -0,0 +1,95 @@
+use crate::cli::{Args, ReplayArgs};
+use crate::crawler::{finish_writer, send_msg, start_writer, Msg};
+use crate::db::{complete_replayed_scan, compute_metrics, open_db};
+use crate::virality::Ranking;
+
+use anyhow::{Result, anyhow};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Writer messages that never made it into the DB, one JSON object per line. Whoever holds a
+// message when the writer dies appends it here; the file is only created on the first spill.
+#[derive(Clone)]
+pub struct DeadLetter {
+    path: String,
+    file: Arc<Mutex<Option<File>>>,
+    count: Arc<AtomicUsize>,
+}
+
+impl DeadLetter {
+    pub fn new(path: &str) -> DeadLetter {
+        DeadLetter { path: path.to_string(), file: Arc::new(Mutex::new(None)), count: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn spill(&self, msg: &Msg) {
+        let mut f = self.file.lock().unwrap();
+        if f.is_none() {
+            match OpenOptions::new().create(true).append(true).open(&self.path) {
+                Ok(file) => *f = Some(file),
+                Err(e) => { eprintln!("[DEAD LETTER] cannot open {}: {e}", self.path); return; }
+            }
+        }
+        let line = match serde_json::to_string(msg) {
+            Ok(l) => l,
+            Err(e) => { eprintln!("[DEAD LETTER] cannot encode message: {e}"); return; }
+        };
+        if let Some(file) = f.as_mut() {
+            match writeln!(file, "{line}") {
+                Ok(()) => { self.count.fetch_add(1, Ordering::Relaxed); }
+                Err(e) => eprintln!("[DEAD LETTER] write to {} failed: {e}", self.path),
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+// Feeds a dead-letter file back through the writer, then marks the aborted scans it touched
+// completed and recomputes their metrics. The file is moved aside first; anything that fails again is spilled to --dead-letter.
+pub async fn run_replay(args: &Args, r: ReplayArgs) -> Result<()> {
+    let path = r.file.unwrap_or_else(|| args.dead_letter.clone());
+    let text = std::fs::read_to_string(&path).map_err(|e| anyhow!("{path}: {e}"))?;
+    let mut msgs = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() { continue; }
+        msgs.push(serde_json::from_str::<Msg>(line).map_err(|e| anyhow!("{path}:{}: {e}", i + 1))?);
+    }
+    if msgs.is_empty() {
+        eprintln!("[REPLAY] {path} is empty");
+        return Ok(());
+    }
+    let scans: BTreeSet<i64> = msgs.iter().filter_map(Msg::scan_id).collect();
+
+    let aside = format!("{path}.replaying");
+    std::fs::rename(&path, &aside)?;
+
+    let dl = DeadLetter::new(&args.dead_letter);
+    let (tx, wt) = start_writer(args, &dl);
+    let total = msgs.len();
+    for m in msgs {
+        send_msg(&tx, &dl, m).await;
+    }
+    drop(tx);
+    let res = finish_writer(wt, &dl);
+    // every message is now either in the DB or back in a dead-letter file
+    std::fs::remove_file(&aside)?;
+    let stats = res?;
+
+    let ranking = Ranking::from_args(args)?;
+    let conn = open_db(&args.db)?;
+    for scan_id in scans {
+        complete_replayed_scan(&conn, scan_id)?;
+        compute_metrics(&conn, scan_id, &ranking)?;
+    }
+    eprintln!("[REPLAY] {total} messages from {path}: {}", stats.summary());
+    Ok(())
+}
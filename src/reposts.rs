@@ -0,0 +1,21 @@
+use crate::cli::{Args, RepostsArgs};
+use crate::db::{find_reposts, open_db};
+
+use anyhow::{anyhow, Result};
+
+pub fn run_reposts(args: &Args, r: RepostsArgs) -> Result<()> {
+    if !(0..=63).contains(&r.threshold) { return Err(anyhow!("--threshold must be between 0 and 63")); }
+    let conn = open_db(&args.db)?;
+    let pairs = find_reposts(&conn, r.threshold, r.subreddit.as_deref(), r.limit)?;
+    if pairs.is_empty() {
+        eprintln!("[REPOSTS] No image pairs within {} bits", r.threshold);
+        return Ok(());
+    }
+    println!("dist  original (subreddit)  ->  repost (subreddit)");
+    for (d, a, asub, b, bsub, aurl, burl) in pairs {
+        println!("{d:>4}  {a} (r/{asub})  ->  {b} (r/{bsub})");
+        println!("      {aurl}");
+        println!("      {burl}");
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use crate::cli::Args;
+use crate::db::open_db;
+use crate::utils::fmt_ts;
+
+use anyhow::Result;
+
+const TABLES: &[&str] = &[
+    "subreddits", "posts", "comments", "media", "scans",
+    "post_snapshots", "comment_snapshots", "subreddit_snapshots",
+    "post_metrics", "comment_metrics", "subreddit_metrics", "trending", "content_versions", "state_events", "exports",
+];
+
+pub fn run_stats(args: &Args) -> Result<()> {
+    let conn = open_db(&args.db)?;
+    let (version, name): (i64, String) = conn.query_row(
+        "SELECT version, name FROM schema_version ORDER BY version DESC LIMIT 1", [], |r| Ok((r.get(0)?, r.get(1)?))
+    )?;
+    println!("{}  (schema v{version}, {name})", args.db);
+
+
+    println!();
+    for t in TABLES {
+        let n: i64 = conn.query_row(&format!("SELECT count(*) FROM {t}"), [], |r| r.get(0))?;
+        println!("{t:<20} {n:>10}");
+    }
+
+
+    let (first, last): (Option<i64>, Option<i64>) = conn.query_row(
+        "SELECT MIN(scanned_at), MAX(scanned_at) FROM scans", [], |r| Ok((r.get(0)?, r.get(1)?))
+    )?;
+    if let (Some(first), Some(last)) = (first, last) {
+        println!();
+        println!("scans from {} to {}", fmt_ts(first), fmt_ts(last));
+        let mut stmt = conn.prepare("SELECT status, count(*) FROM scans GROUP BY status ORDER BY status")?;
+        let by_status = stmt.query_map([], |r| Ok(format!("{} {}", r.get::<_, i64>(1)?, r.get::<_, String>(0)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        println!("scans {}", by_status.join(", "));
+    }
+    let (stored, failed): (i64, i64) = conn.query_row(
+        "SELECT count(*) FILTER (WHERE content_hash IS NOT NULL OR data_base64 IS NOT NULL), count(*) FILTER (WHERE fetch_error IS NOT NULL) FROM media",
+        [], |r| Ok((r.get(0)?, r.get(1)?))
+    )?;
+    println!("images stored {stored}, failed {failed}");
+
+
+    let mut stmt = conn.prepare(r#"
+        SELECT s.name, count(DISTINCT p.id), count(c.id)
+        FROM subreddits s
+        JOIN posts p ON p.subreddit_id = s.id
+        LEFT JOIN comments c ON c.post_id = p.id
+        GROUP BY s.name
+        ORDER BY count(DISTINCT p.id) DESC, s.name
+        LIMIT 10
+    "#)?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?, r.get::<_, i64>(2)?)))?;
+    println!();
+    println!("{:<24} {:>8} {:>10}", "subreddit", "posts", "comments");
+    for row in rows {
+        let (name, posts, comments) = row?;
+        println!("{:<24} {posts:>8} {comments:>10}", format!("r/{name}"));
+    }
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use governor::{
     Quota, RateLimiter,
     clock::MonotonicClock,
-    state::{InMemoryState, direct::NotKeyed},
+    state::{InMemoryState, direct::NotKeyed, keyed::DefaultKeyedStateStore},
 };
 use std::{num::NonZeroU32, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -14,7 +14,16 @@ pub fn make_limiter(rpm: u32) -> Limiter {
     Arc::new(RateLimiter::direct(q))
 }
 
+// Keyed by host, so a slow or strict CDN only throttles itself.
+pub type HostLimiter = Arc<RateLimiter<String, DefaultKeyedStateStore<String>, MonotonicClock>>;
+
+pub fn make_host_limiter(rpm: u32) -> HostLimiter {
+    let q = Quota::per_minute(NonZeroU32::new(rpm.max(1)).unwrap());
+    Arc::new(RateLimiter::keyed(q))
+}
+
 static COOLDOWN_UNTIL: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
 
 pub async fn gate(l: &Limiter) {
     let now = now_secs();
@@ -25,7 +34,9 @@ pub async fn gate(l: &Limiter) {
     l.until_ready().await;
 }
 
+// Called once per 429 response, which is also what `take_rate_limited` counts.
 pub fn set_cooldown_secs(secs: u64) {
+    RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
     let until = now_secs() + secs;
     let prev = COOLDOWN_UNTIL.load(Ordering::Relaxed);
     if until > prev {
@@ -33,6 +44,11 @@ pub fn set_cooldown_secs(secs: u64) {
     }
 }
 
+// 429s since the last call
+pub fn take_rate_limited() -> u64 {
+    RATE_LIMITED.swap(0, Ordering::Relaxed)
+}
+
 #[inline]
 fn now_secs() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
@@ -1,18 +1,40 @@
-use anyhow::Result;
 use base64::engine::general_purpose::STANDARD as B64;
 use base64::Engine;
-use reqwest::Client;
+use chrono::{TimeZone, Utc};
+use std::io::Cursor;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub async fn fetch_image_b64(client: &Client, url: &str) -> Result<(Option<String>, Option<String>, Option<i64>)> {
-    let resp = client.get(url).send().await?;
-    let status = resp.status();
-    if !status.is_success() {
-        return Ok((None, None, None));
+pub fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+pub fn fmt_ts(t: i64) -> String {
+    Utc.timestamp_opt(t, 0).single()
+        .map(|d| d.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| t.to_string())
+}
+
+pub fn to_b64(bytes: &[u8]) -> String {
+    B64.encode(bytes)
+}
+
+// Reads only the header, so it is cheap even for large images.
+pub fn image_dims(bytes: &[u8]) -> Option<(i64, i64)> {
+    let (w, h) = image::ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?.into_dimensions().ok()?;
+    Some((w as i64, h as i64))
+}
+
+// 64-bit difference hash: 9x8 grayscale thumbnail, one bit per left/right brightness comparison.
+// Near-identical images (rescaled, recompressed) end up a few bits apart.
+pub fn dhash(bytes: &[u8]) -> Option<i64> {
+    let img = image::load_from_memory(bytes).ok()?;
+    let small = img.grayscale().resize_exact(9, 8, image::imageops::FilterType::Triangle).to_luma8();
+    let mut h: u64 = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            h = (h << 1) | bit as u64;
+        }
     }
-    let mime = resp.headers().get(reqwest::header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok()).map(|s| s.to_string());
-    let bytes = resp.bytes().await?;
-    let len = bytes.len() as i64;
-    let b64 = B64.encode(&bytes);
-    Ok((Some(b64), mime, Some(len)))
+    Some(h as i64)
 }
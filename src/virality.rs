@@ -0,0 +1,216 @@
+use crate::cli::Args;
+
+use anyhow::{anyhow, Result};
+use duckdb::{params, Connection};
+
+// What a model sees of a post in one scan. Rates are None without a previous scan or a creation time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PostSignals {
+    pub score_vph: Option<f64>,
+    pub comments_vph: Option<f64>,
+    pub score_per_hour: Option<f64>,
+    pub comments_per_hour: Option<f64>,
+    pub age_hours: Option<f64>,
+}
+
+pub trait ViralityModel: Send + Sync {
+    // Stored with each trending row; baselines only compare scores from the same model.
+    fn name(&self) -> String;
+    fn score(&self, p: &PostSignals) -> f64;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Weights {
+    pub score_vph: f64,
+    pub comments_vph: f64,
+    pub score_per_hour: f64,
+    pub comments_per_hour: f64,
+}
+
+impl Weights {
+    // "score_vph=0.6,comments_vph=0.4"; signals left out weigh 0
+    pub fn parse(s: &str) -> Result<Weights> {
+        let mut w = Weights::default();
+        for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (k, v) = part.split_once('=').ok_or_else(|| anyhow!("bad weight '{part}', expected name=value"))?;
+            let v: f64 = v.trim().parse().map_err(|_| anyhow!("bad weight value in '{part}'"))?;
+            match k.trim() {
+                "score_vph" => w.score_vph = v,
+                "comments_vph" => w.comments_vph = v,
+                "score_per_hour" => w.score_per_hour = v,
+                "comments_per_hour" => w.comments_per_hour = v,
+                other => return Err(anyhow!("unknown virality signal '{other}'")),
+            }
+        }
+        Ok(w)
+    }
+
+    fn apply(&self, p: &PostSignals) -> f64 {
+        self.score_vph * p.score_vph.unwrap_or(0.0)
+            + self.comments_vph * p.comments_vph.unwrap_or(0.0)
+            + self.score_per_hour * p.score_per_hour.unwrap_or(0.0)
+            + self.comments_per_hour * p.comments_per_hour.unwrap_or(0.0)
+    }
+
+    fn label(&self) -> String {
+        format!("score_vph={},comments_vph={},score_per_hour={},comments_per_hour={}",
+                self.score_vph, self.comments_vph, self.score_per_hour, self.comments_per_hour)
+    }
+}
+
+// The weighted sum as is. With the default weights this is the old virality_score.
+pub struct Linear {
+    pub weights: Weights,
+}
+
+impl ViralityModel for Linear {
+    fn name(&self) -> String { format!("linear({})", self.weights.label()) }
+
+    fn score(&self, p: &PostSignals) -> f64 { self.weights.apply(p) }
+}
+
+// The weighted sum times 2^(-age / half-life), so a post that stops growing sinks. Posts of
+// unknown age are not decayed.
+pub struct Decay {
+    pub weights: Weights,
+    pub half_life_hours: f64,
+}
+
+impl ViralityModel for Decay {
+    fn name(&self) -> String { format!("decay({};half_life_hours={})", self.weights.label(), self.half_life_hours) }
+
+    fn score(&self, p: &PostSignals) -> f64 {
+        let age = p.age_hours.unwrap_or(0.0).max(0.0);
+        self.weights.apply(p) * (-age * std::f64::consts::LN_2 / self.half_life_hours).exp()
+    }
+}
+
+pub struct Ranking {
+    pub model: Box<dyn ViralityModel>,
+    pub baseline_scans: usize,
+}
+
+impl Ranking {
+    pub fn from_args(args: &Args) -> Result<Ranking> {
+        let weights = Weights::parse(&args.virality_weights)?;
+        let model: Box<dyn ViralityModel> = match args.virality_model.as_str() {
+            "linear" => Box::new(Linear { weights }),
+            _ => {
+                if args.half_life_hours <= 0.0 { return Err(anyhow!("--half-life-hours must be positive")); }
+                Box::new(Decay { weights, half_life_hours: args.half_life_hours })
+            }
+        };
+        Ok(Ranking { model, baseline_scans: args.baseline_scans })
+    }
+
+    // Scores the scan's post_metrics rows with the model, then ranks its posts in `trending` by
+    // their z-score against their subreddit: this scan's scores plus those of the previous
+    // `baseline_scans` completed scans under the same model. Expects post_metrics to be current.
+    pub fn compute(&self, conn: &Connection, scan_id: i64) -> Result<()> {
+        let mut stmt = conn.prepare(r#"
+            SELECT post_id, listing_sort, listing_time, score_vph, comments_vph, score_per_hour, comments_per_hour, age_hours
+            FROM post_metrics WHERE scan_id = ?
+        "#)?;
+        let rows = stmt.query_map(params![scan_id], |r| Ok((
+            r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get::<_, String>(2)?,
+            PostSignals {
+                score_vph: r.get(3)?,
+                comments_vph: r.get(4)?,
+                score_per_hour: r.get(5)?,
+                comments_per_hour: r.get(6)?,
+                age_hours: r.get(7)?,
+            },
+        )))?.collect::<std::result::Result<Vec<_>, _>>()?;
+
+        conn.execute_batch(r#"
+        CREATE OR REPLACE TEMP TABLE virality_stage (post_id VARCHAR, listing_sort VARCHAR, listing_time VARCHAR, score DOUBLE);
+        "#)?;
+        let mut app = conn.appender("virality_stage")?;
+        for (post_id, sort, time, signals) in &rows {
+            app.append_row(params![post_id, sort, time, self.model.score(signals)])?;
+        }
+        app.flush()?;
+        drop(app);
+
+        let model = self.model.name();
+        conn.execute(r#"
+            UPDATE post_metrics SET virality_score = v.score
+            FROM virality_stage v
+            WHERE post_metrics.scan_id = ? AND post_metrics.post_id = v.post_id
+              AND post_metrics.listing_sort = v.listing_sort AND post_metrics.listing_time = v.listing_time
+        "#, params![scan_id])?;
+        conn.execute("DELETE FROM trending WHERE scan_id = ?", params![scan_id])?;
+        conn.execute(&format!(r#"
+            INSERT INTO trending
+            WITH cur AS (
+                -- a post seen in several listings counts once, at its best
+                SELECT v.post_id, p.subreddit_id, MAX(v.score) AS virality_score
+                FROM virality_stage v JOIN posts p ON p.id = v.post_id
+                GROUP BY v.post_id, p.subreddit_id
+            ),
+            base AS (
+                SELECT subreddit_id, AVG(virality_score) AS mean, STDDEV_POP(virality_score) AS sd
+                FROM (
+                    SELECT subreddit_id, virality_score FROM cur
+                    UNION ALL
+                    SELECT subreddit_id, virality_score FROM trending
+                    WHERE model = ? AND scan_id IN (
+                        SELECT id FROM scans WHERE status = 'completed' AND id < {scan} ORDER BY id DESC LIMIT {k}
+                    )
+                )
+                GROUP BY subreddit_id
+            ),
+            scored AS (
+                SELECT cur.*, base.mean, base.sd,
+                       CASE WHEN base.sd > 0 THEN (cur.virality_score - base.mean) / base.sd ELSE 0 END AS z_score
+                FROM cur JOIN base USING (subreddit_id)
+            )
+            SELECT
+                {scan} AS scan_id, post_id, subreddit_id, ? AS model, virality_score,
+                mean AS baseline_mean, sd AS baseline_sd, z_score,
+                row_number() OVER (ORDER BY z_score DESC, virality_score DESC, post_id) AS rank,
+                row_number() OVER (PARTITION BY subreddit_id ORDER BY virality_score DESC, post_id) AS subreddit_rank
+            FROM scored
+        "#, scan = scan_id, k = self.baseline_scans), params![model, model])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_parse_reads_named_signals() {
+        let w = Weights::parse(" score_vph=0.6, comments_vph = 0.4 ,").unwrap();
+        assert_eq!((w.score_vph, w.comments_vph, w.score_per_hour, w.comments_per_hour), (0.6, 0.4, 0.0, 0.0));
+        let w = Weights::parse("").unwrap();
+        assert_eq!(w.score_vph, 0.0);
+    }
+
+    #[test]
+    fn weights_parse_rejects_bad_input() {
+        assert!(Weights::parse("score_vph").is_err());
+        assert!(Weights::parse("score_vph=abc").is_err());
+        assert!(Weights::parse("upvotes=1").is_err());
+    }
+
+    #[test]
+    fn decay_halves_per_half_life() {
+        let m = Decay { weights: Weights { score_vph: 1.0, ..Default::default() }, half_life_hours: 12.0 };
+        let at = |age: Option<f64>| m.score(&PostSignals { score_vph: Some(100.0), age_hours: age, ..Default::default() });
+        assert!((at(Some(0.0)) - 100.0).abs() < 1e-9);
+        assert!((at(Some(12.0)) - 50.0).abs() < 1e-9);
+        assert!((at(Some(24.0)) - 25.0).abs() < 1e-9);
+        // unknown or negative ages are not decayed
+        assert!((at(None) - 100.0).abs() < 1e-9);
+        assert!((at(Some(-5.0)) - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn missing_signals_count_as_zero() {
+        let m = Decay { weights: Weights::parse("score_vph=1,comments_vph=2").unwrap(), half_life_hours: 24.0 };
+        let s = m.score(&PostSignals { comments_vph: Some(3.0), age_hours: Some(0.0), ..Default::default() });
+        assert!((s - 6.0).abs() < 1e-9);
+    }
+}
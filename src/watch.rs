@@ -0,0 +1,105 @@
+use crate::cli::{Args, WatchArgs};
+use crate::crawler::{close_scan, close_workers, crawl_with, load_targets, open_workers, revive_workers};
+use crate::db::{open_db, start_scan};
+use crate::nav::PoliteKnobs;
+use crate::throttle::Limiter;
+use crate::utils::now_secs;
+use crate::virality::Ranking;
+
+use anyhow::{Result, anyhow};
+use chrono::{TimeZone, Utc};
+use cron::Schedule;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+fn next_cron(schedule: &Schedule, after: i64) -> Option<i64> {
+    let t = Utc.timestamp_opt(after, 0).single()?;
+    schedule.after(&t).next().map(|n| n.timestamp())
+}
+
+// Runs scans forever. Subreddits with their own `interval` column follow it; the rest follow
+// `--cron` if given, else `--interval-mins`. Browser sessions stay open between scans.
+pub async fn run_watch(args: Args, w: WatchArgs, limiter: Limiter, knobs: PoliteKnobs) -> Result<()> {
+    let subs = load_targets(&args)?;
+    let ranking = Ranking::from_args(&args)?;
+    if args.workers == 0 { return Err(anyhow!("--workers must be at least 1")); }
+    let schedule = match &w.cron {
+        Some(expr) => Some(Schedule::from_str(expr).map_err(|e| anyhow!("bad --cron '{expr}': {e}"))?),
+        None => None,
+    };
+    let default_every = (w.interval_mins.max(1) * 60) as i64;
+
+    let next_for = |i: usize, from: i64| -> i64 {
+        match (subs[i].interval_mins, &schedule) {
+            (Some(m), _) => from + (m.max(1) * 60) as i64,
+            (None, Some(s)) => next_cron(s, from).unwrap_or(from + default_every),
+            (None, None) => from + default_every,
+        }
+    };
+
+    // with a cron schedule, default subs wait for the first fire; everything else starts now
+    let start = now_secs();
+    let mut next_due: Vec<i64> = (0..subs.len())
+        .map(|i| if subs[i].interval_mins.is_none() && schedule.is_some() { next_for(i, start) } else { start })
+        .collect();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let wake = Arc::new(Notify::new());
+    {
+        let stop = stop.clone();
+        let wake = wake.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("[WATCH] stopping after the current scan (Ctrl-C again to abort)");
+                stop.store(true, Ordering::Relaxed);
+                wake.notify_one();
+                if tokio::signal::ctrl_c().await.is_ok() { std::process::exit(130); }
+            }
+        });
+    }
+
+    let slots = args.workers.min(subs.len());
+    let mut workers = open_workers(&args, slots).await;
+
+    while !stop.load(Ordering::Relaxed) {
+        let now = now_secs();
+        let due: Vec<usize> = (0..subs.len()).filter(|&i| next_due[i] <= now).collect();
+        if due.is_empty() {
+            let soonest = next_due.iter().copied().min().unwrap_or(now + default_every);
+            eprintln!("[WATCH] next scan in {}s", soonest - now);
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs((soonest - now).max(1) as u64)) => {}
+                _ = wake.notified() => {}
+            }
+            continue;
+        }
+
+        revive_workers(&args, &mut workers).await;
+
+        let conn = open_db(&args.db)?;
+        let scan_id = start_scan(&conn, &args)?;
+        drop(conn);
+
+        let batch = due.iter().map(|&i| subs[i].clone()).collect::<Vec<_>>();
+        // a failed crawl has already closed its workers; empty slots get reopened next round
+        let res = match crawl_with(&args, batch, workers, limiter.clone(), knobs, scan_id).await {
+            Ok((stats, ws)) => { workers = ws; Ok(stats) }
+            Err(e) => { workers = (0..slots).map(|_| None).collect(); Err(e) }
+        };
+        match close_scan(&args.db, scan_id, &ranking, res) {
+            Ok(stats) => eprintln!("[SCAN {scan_id}] Saved {} from {} subreddits", stats.summary(), due.len()),
+            Err(e) => eprintln!("[SCAN {scan_id}] failed: {e:#}"),
+        }
+
+        let finished = now_secs();
+        for i in due {
+            next_due[i] = next_for(i, finished);
+        }
+    }
+
+    close_workers(workers).await;
+    Ok(())
+}